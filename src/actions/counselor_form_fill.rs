@@ -1,22 +1,28 @@
+pub mod nonblocking;
 mod structs;
 
 use crate::{
-    config::User,
+    config::{Schedule, User},
     cpdaily::crypto::{
         ciphers::md5,
         traits::first_v2::{self, FirstV2},
     },
 };
 use anyhow::{anyhow, Result};
+use chrono::NaiveDateTime;
 use reqwest::{blocking::Client, StatusCode};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use std::time::Duration;
 use structs::*;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct CounselorFormFillAction {
     pub form_data: Vec<QA>,
     pub force_submit: bool,
+    // overrides the schedule of the user in daemon mode
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -25,16 +31,44 @@ pub struct QA {
     pub answer: String,
 }
 
+// what an action did with the forms it was given
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FormFillStats {
+    pub handled: u32,
+    pub submitted: u32,
+    pub skipped: u32,
+    // how long until the forms that are listed but not open yet open
+    pub opens_in: Vec<Duration>,
+}
+
 pub fn perform(
     session: &Client,
     base_url: &str,
     config: &CounselorFormFillAction,
     user: &User,
     encryptor: &dyn FirstV2,
+    stats: &mut FormFillStats,
+    dry_run: bool,
 ) -> Result<()> {
     let form_list = get_form_list(session, base_url, 20, 1)?;
 
     for form in form_list {
+        stats.handled += 1;
+        if let Some(opens_in) = opens_in(&form) {
+            crate::logger::log(sentry::Breadcrumb {
+                category: Some("counselor_form_fill".to_string()),
+                message: Some(format!(
+                    "skipping [{}]{} as it opens at {}",
+                    &form.wid, &form.subject, &form.start_time
+                )),
+                level: sentry::Level::Info,
+                ..Default::default()
+            });
+            stats.skipped += 1;
+            stats.opens_in.push(opens_in);
+            continue;
+        }
+
         if form.is_handled == 1 && !config.force_submit {
             // skip filled forms
             crate::logger::log(sentry::Breadcrumb {
@@ -46,6 +80,7 @@ pub fn perform(
                 level: sentry::Level::Info,
                 ..Default::default()
             });
+            stats.skipped += 1;
             continue;
         }
 
@@ -89,7 +124,13 @@ pub fn perform(
             instance_wid: form.instance_wid,
         };
 
+        if dry_run {
+            print_dry_run(&form_data, user, encryptor)?;
+            continue;
+        }
+
         post_form(session, base_url, &form_data, user, encryptor)?;
+        stats.submitted += 1;
 
         crate::logger::log(sentry::Breadcrumb {
             category: Some("counselor_form_fill".to_string()),
@@ -102,20 +143,33 @@ pub fn perform(
     Ok(())
 }
 
-fn fill_fields(
-    form_fields: &mut Vec<Value>,
-    config: &CounselorFormFillAction,
-) -> anyhow::Result<()> {
-    for field in form_fields.iter_mut() {
-        let field_type: i32 = field
-            .get("fieldType")
-            .unwrap()
-            .as_str()
-            .unwrap()
-            .parse::<i32>()
-            .unwrap();
+fn parse_time(time: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M"))
+        .ok()
+}
+
+// measured on the server clock, so the local time zone does not matter
+fn opens_in(form: &CollectorFormInstance) -> Option<Duration> {
+    let start = parse_time(&form.start_time)?;
+    let now = parse_time(&form.current_time)?;
+    (start - now).to_std().ok().filter(|d| !d.is_zero())
+}
+
+// a string the server is expected to send, `owner` names what it belongs to
+fn get_str<'a>(value: &'a Value, key: &str, owner: &str) -> anyhow::Result<&'a str> {
+    value
+        .get(key)
+        .and_then(Value::as_str)
+        .ok_or_else(|| anyhow!("Missing {} in \"{}\"", key, owner))
+}
 
-        let title = field.get("title").unwrap().as_str().unwrap().to_string();
+fn fill_fields(form_fields: &mut [Value], config: &CounselorFormFillAction) -> anyhow::Result<()> {
+    for field in form_fields.iter_mut() {
+        let title = get_str(field, "title", "form field")?.to_string();
+        let field_type: i32 = get_str(field, "fieldType", &title)?
+            .parse()
+            .map_err(|_| anyhow!("Field \"{}\" has a non-numeric fieldType", &title))?;
         let answer = get_answer_from_config(config, &title);
 
         if let Some(answer_str) = answer {
@@ -125,7 +179,7 @@ fn fill_fields(
                     // text
                     field
                         .as_object_mut()
-                        .unwrap()
+                        .ok_or_else(|| anyhow!("Field \"{}\" is not an object", &title))?
                         .insert("value".to_string(), json!(&answer_str));
 
                     crate::logger::log(sentry::Breadcrumb {
@@ -137,29 +191,28 @@ fn fill_fields(
                 }
                 2 => {
                     // single choice
-                    let options: Vec<Value> = field
+                    let items = field
                         .get("fieldItems")
-                        .unwrap()
-                        .as_array()
-                        .unwrap()
-                        .to_vec()
-                        .into_iter()
-                        .filter(|item| {
-                            item.get("content")
-                                .unwrap()
-                                .as_str()
-                                .unwrap()
-                                .contains(&answer_str)
-                        })
-                        .collect();
-                    assert_eq!(options.len(), 1, "Unexpected filtered option length");
-                    let f = field.as_object_mut().unwrap();
-                    let wid = options[0]
-                        .get("itemWid")
-                        .unwrap()
-                        .as_str()
-                        .unwrap()
-                        .to_string();
+                        .and_then(Value::as_array)
+                        .ok_or_else(|| anyhow!("Field \"{}\" has no fieldItems", &title))?;
+                    let mut options: Vec<Value> = vec![];
+                    for item in items {
+                        if get_str(item, "content", &title)?.contains(&answer_str) {
+                            options.push(item.clone());
+                        }
+                    }
+                    if options.len() != 1 {
+                        return Err(anyhow!(
+                            "\"{}\" matches {} options of \"{}\", expected one",
+                            &answer_str,
+                            options.len(),
+                            &title
+                        ));
+                    }
+                    let wid = get_str(&options[0], "itemWid", &title)?.to_string();
+                    let f = field
+                        .as_object_mut()
+                        .ok_or_else(|| anyhow!("Field \"{}\" is not an object", &title))?;
                     f.insert("fieldItems".to_string(), serde_json::Value::Array(options));
                     f.insert("value".to_string(), json!(&wid));
 
@@ -174,48 +227,58 @@ fn fill_fields(
                     });
                 }
                 3 => {
-                    unimplemented!("multi choice");
+                    return Err(anyhow!(
+                        "Multi choice field \"{}\" is not supported",
+                        &title
+                    ));
                 }
                 4 => {
-                    unimplemented!("upload photo");
+                    return Err(anyhow!(
+                        "Photo upload field \"{}\" is not supported",
+                        &title
+                    ));
                 }
                 _ => {
                     // other
-                    unimplemented!("unimplemented field type");
+                    return Err(anyhow!(
+                        "Field \"{}\" of type {} is not supported",
+                        &title,
+                        field_type
+                    ));
                 }
             }
-        } else if field.get("isRequired").unwrap().as_bool().unwrap() {
+        } else if field
+            .get("isRequired")
+            .and_then(Value::as_bool)
+            .ok_or_else(|| anyhow!("Field \"{}\" has no isRequired", &title))?
+        {
             // required field
-            return Err(anyhow!(
-                "Required field \"{}\" not found",
-                field.get("title").unwrap().as_str().unwrap()
-            ));
+            return Err(anyhow!("Required field \"{}\" not found", &title));
         }
     }
     Ok(())
 }
 
-fn get_form_list(
-    session: &Client,
-    base_url: &str,
-    page_size: u32,
-    page_number: u32,
-) -> Result<Vec<CollectorFormInstance>> {
-    let result = session
-        .post(format!(
-            "{}/wec-counselor-collector-apps/stu/collector/queryCollectorProcessingList",
-            base_url
-        ))
-        .json(&json!({
-            "pageSize": page_size,
-            "pageNumber": page_number,
-        }))
-        .send()?;
+const FORM_LIST_PATH: &str =
+    "/wec-counselor-collector-apps/stu/collector/queryCollectorProcessingList";
+const FORM_DETAIL_PATH: &str = "/wec-counselor-collector-apps/stu/collector/detailCollector";
+const FORM_FIELDS_PATH: &str = "/wec-counselor-collector-apps/stu/collector/getFormFields";
+const SUBMIT_FORM_PATH: &str = "/wec-counselor-collector-apps/stu/collector/submitForm";
+
+// the requests and responses are shared with the async calls in nonblocking
+
+fn form_list_request(page_size: u32, page_number: u32) -> Value {
+    json!({
+        "pageSize": page_size,
+        "pageNumber": page_number,
+    })
+}
 
-    match result.status() {
+fn parse_form_list(status: StatusCode, body: &str) -> Result<Vec<CollectorFormInstance>> {
+    match status {
         StatusCode::OK => {
             let resp: CounselorResponse<CounselorPaginator<CollectorFormInstance>> =
-                result.json()?;
+                serde_json::from_str(body)?;
             let data = resp.datas;
             if data.total_size == 0 {
                 Ok(vec![])
@@ -224,34 +287,94 @@ fn get_form_list(
             }
         }
         StatusCode::NOT_FOUND => Err(anyhow!("Form list not found")),
-        _ => {
-            // TODO: refine
-            unreachable!()
-        }
+        status => Err(anyhow!("Unexpected response {} on form list", status)),
+    }
+}
+
+// the form list redirects to the login page once MOD_AUTH_CAS has expired
+fn parse_session_valid(status: StatusCode, body: &str) -> Result<bool> {
+    if status.is_server_error() {
+        return Err(anyhow!("Unexpected response {} on form list", status));
     }
+    Ok(status == StatusCode::OK
+        && serde_json::from_str::<CounselorResponse<Value>>(body)
+            .map(|resp| resp.code == "0")
+            .unwrap_or(false))
+}
+
+fn form_detail_request(wid: &str, instance_wid: Option<i64>) -> Value {
+    json!({
+        "collectorWid": wid,
+        "instanceWid": instance_wid,
+    })
+}
+
+fn form_fields_request(wid: &str, form_wid: &str, page_size: u32, page_number: u32) -> Value {
+    json!({
+        "pageSize": page_size,
+        "pageNumber": page_number,
+        "formWid": form_wid,
+        "collectorWid": wid,
+    })
 }
 
-fn get_form_detail(
+fn parse_submit(status: StatusCode, text: &str) -> Result<()> {
+    crate::logger::log(sentry::Breadcrumb {
+        category: Some("counselor_form_fill".to_string()),
+        message: Some(format!("submitForm returns {} {}", status, text)),
+        level: sentry::Level::Debug,
+        ..Default::default()
+    });
+
+    let resp: Value = serde_json::from_str(text)
+        .map_err(|_| anyhow!("Unexpected response {} on form submit", status))?;
+    if status != StatusCode::OK || resp["code"] != "0" {
+        return Err(anyhow!(
+            "Form submit rejected: {}",
+            resp["message"].as_str().unwrap_or(text)
+        ));
+    }
+    Ok(())
+}
+
+pub fn get_form_list(
+    session: &Client,
+    base_url: &str,
+    page_size: u32,
+    page_number: u32,
+) -> Result<Vec<CollectorFormInstance>> {
+    let result = session
+        .post(format!("{}{}", base_url, FORM_LIST_PATH))
+        .json(&form_list_request(page_size, page_number))
+        .send()?;
+    let status = result.status();
+    parse_form_list(status, &result.text()?)
+}
+
+pub fn is_session_valid(session: &Client, base_url: &str) -> Result<bool> {
+    let result = session
+        .post(format!("{}{}", base_url, FORM_LIST_PATH))
+        .json(&form_list_request(1, 1))
+        .send()?;
+    let status = result.status();
+    parse_session_valid(status, &result.text()?)
+}
+
+pub fn get_form_detail(
     session: &Client,
     base_url: &str,
     wid: &str,
     instance_wid: Option<i64>,
 ) -> Result<FormDetail> {
     let result: CounselorResponse<FormDetail> = session
-        .post(format!(
-            "{}/wec-counselor-collector-apps/stu/collector/detailCollector",
-            base_url
-        ))
-        .json(&json!({
-            "collectorWid": wid,
-            "instanceWid": instance_wid,
-        }))
+        .post(format!("{}{}", base_url, FORM_DETAIL_PATH))
+        .json(&form_detail_request(wid, instance_wid))
         .send()?
         .json()?;
     Ok(result.datas)
 }
 
-fn get_form_fields(
+pub fn get_form_fields(
     session: &Client,
     base_url: &str,
     wid: &str,
@@ -260,28 +383,18 @@ fn get_form_fields(
     page_number: u32,
 ) -> Result<Vec<Value>> {
     let result: CounselorResponse<CounselorPaginator<Value>> = session
-        .post(format!(
-            "{}/wec-counselor-collector-apps/stu/collector/getFormFields",
-            base_url
-        ))
-        .json(&json!({
-            "pageSize": page_size,
-            "pageNumber": page_number,
-            "formWid": form_wid,
-            "collectorWid": wid,
-        }))
+        .post(format!("{}{}", base_url, FORM_FIELDS_PATH))
+        .json(&form_fields_request(wid, form_wid, page_size, page_number))
         .send()?
         .json()?;
     Ok(result.datas.rows)
 }
 
-fn post_form(
-    session: &Client,
-    base_url: &str,
+fn build_request(
     form_data: &FormContentForSubmit,
     user: &User,
     encryptor: &dyn FirstV2,
-) -> Result<()> {
+) -> Result<FormSubmitRequest> {
     let json_stringifyed_form = serde_json::to_string(form_data)?;
     let encrypted_form = encryptor.encrypt(&json_stringifyed_form, first_v2::KeyType::F)?;
     let key = encryptor.get_key(first_v2::KeyType::F);
@@ -301,17 +414,47 @@ fn post_form(
         user_id: user.username.clone(),
         version: "first_v2".to_string(),
     };
+    Ok(payload)
+}
+
+// show what would be posted, with the encrypted body decrypted back
+fn print_dry_run(
+    form_data: &FormContentForSubmit,
+    user: &User,
+    encryptor: &dyn FirstV2,
+) -> Result<()> {
+    let payload = build_request(form_data, user, encryptor)?;
+    let decrypted = encryptor.decrypt(&payload.body_string, first_v2::KeyType::F)?;
+    let decrypted: Value = serde_json::from_str(&decrypted)?;
+
+    // one print, so users running at once do not interleave
+    let wid = &form_data.collect_wid;
+    print!(
+        "[{}] form content:\n{}\n[{}] submit request:\n{}\n[{}] decrypted body:\n{}\n",
+        wid,
+        serde_json::to_string_pretty(form_data)?,
+        wid,
+        serde_json::to_string_pretty(&payload)?,
+        wid,
+        serde_json::to_string_pretty(&decrypted)?
+    );
+    Ok(())
+}
+
+fn post_form(
+    session: &Client,
+    base_url: &str,
+    form_data: &FormContentForSubmit,
+    user: &User,
+    encryptor: &dyn FirstV2,
+) -> Result<()> {
+    let payload = build_request(form_data, user, encryptor)?;
     let result = session
-        .post(format!(
-            "{}/wec-counselor-collector-apps/stu/collector/submitForm",
-            base_url
-        ))
+        .post(format!("{}{}", base_url, SUBMIT_FORM_PATH))
         .json(&payload)
         .send()?;
-
-    println!("{}", result.status());
-    println!("{}", result.text()?);
-    Ok(())
+    let status = result.status();
+    parse_submit(status, &result.text()?)
 }
 
 fn get_answer_from_config(config: &CounselorFormFillAction, question: &str) -> Option<String> {
@@ -333,7 +476,12 @@ mod tests {
     };
 
     use crate::actions::counselor_form_fill::{
-        CollectorFormInstance, CounselorPaginator, CounselorResponse, FormDetail,
+        CollectorFormInstance, CounselorPaginator, CounselorResponse, FormContentForSubmit,
+        FormDetail,
+    };
+    use crate::cpdaily::crypto::{
+        ciphers::base64,
+        traits::first_v2::{FirstV2, KeyType},
     };
 
     #[test]
@@ -404,11 +552,8 @@ mod tests {
         // assert_eq!(parsed_response.datas.collector.attachment_names, None);
         // assert_eq!(parsed_response.datas.collector.attachment_sizes, None);
         assert_eq!(parsed_response.datas.collector.is_user_submit, 1);
-        assert_eq!(parsed_response.datas.collector.fetch_stu_location, true);
-        assert_eq!(
-            parsed_response.datas.collector.is_location_failed_sub,
-            false
-        );
+        assert!(parsed_response.datas.collector.fetch_stu_location);
+        assert!(!parsed_response.datas.collector.is_location_failed_sub);
         assert_eq!(
             parsed_response.datas.collector.address,
             Some("test123".to_string())
@@ -465,4 +610,164 @@ mod tests {
         let form_list = super::get_form_list(&client, &platform_host, 20, 1).unwrap();
         println!("{:#?}", form_list);
     }
+
+    #[test]
+    fn test_fill_fields() {
+        let config = super::CounselorFormFillAction {
+            form_data: vec![
+                super::QA {
+                    question: "体温".to_string(),
+                    answer: "36.5".to_string(),
+                },
+                super::QA {
+                    question: "健康".to_string(),
+                    answer: "是".to_string(),
+                },
+            ],
+            force_submit: false,
+            schedule: None,
+        };
+        let field = |title: &str, field_type: &str, items: serde_json::Value| {
+            serde_json::json!({
+                "title": title,
+                "fieldType": field_type,
+                "isRequired": true,
+                "fieldItems": items,
+            })
+        };
+
+        let mut fields = vec![
+            field("今日体温", "1", serde_json::json!([])),
+            field(
+                "是否健康",
+                "2",
+                serde_json::json!([{"content": "是", "itemWid": "1"}, {"content": "否", "itemWid": "2"}]),
+            ),
+        ];
+        super::fill_fields(&mut fields, &config).unwrap();
+        assert_eq!(fields[0]["value"], "36.5");
+        assert_eq!(fields[1]["value"], "1");
+
+        // unsupported or ambiguous fields are errors, not panics
+        let mut fields = vec![field("是否健康", "3", serde_json::json!([]))];
+        assert!(super::fill_fields(&mut fields, &config).is_err());
+        let mut fields = vec![field(
+            "是否健康",
+            "2",
+            serde_json::json!([{"content": "是", "itemWid": "1"}, {"content": "是的", "itemWid": "2"}]),
+        )];
+        assert!(super::fill_fields(&mut fields, &config).is_err());
+        let mut fields = vec![field("姓名", "1", serde_json::json!([]))];
+        assert!(super::fill_fields(&mut fields, &config).is_err());
+
+        // so is whatever the server left out
+        let mut fields = vec![serde_json::json!({"title": "今日体温"})];
+        assert_eq!(
+            super::fill_fields(&mut fields, &config)
+                .unwrap_err()
+                .to_string(),
+            "Missing fieldType in \"今日体温\""
+        );
+        let mut fields = vec![field(
+            "是否健康",
+            "2",
+            serde_json::json!([{"content": "是"}]),
+        )];
+        assert!(super::fill_fields(&mut fields, &config).is_err());
+        let mut fields = vec![field(
+            "是否健康",
+            "2",
+            serde_json::json!([{"itemWid": "1"}]),
+        )];
+        assert!(super::fill_fields(&mut fields, &config).is_err());
+        let mut fields = vec![serde_json::json!({"title": "姓名", "fieldType": "1"})];
+        assert!(super::fill_fields(&mut fields, &config).is_err());
+    }
+
+    struct PlainEncryptor;
+
+    impl FirstV2 for PlainEncryptor {
+        fn encrypt(&self, text: &str, _: KeyType) -> anyhow::Result<String> {
+            Ok(base64::encode(text.as_bytes()))
+        }
+
+        fn decrypt(&self, text: &str, _: KeyType) -> anyhow::Result<String> {
+            Ok(String::from_utf8(base64::decode(text)?)?)
+        }
+
+        fn get_key(&self, _: KeyType) -> String {
+            "key".to_string()
+        }
+    }
+
+    #[test]
+    fn test_build_request() {
+        let user = crate::config::test_user("10000", "alice");
+        let form_data = FormContentForSubmit {
+            form_wid: "3456".to_string(),
+            address: user.address.clone(),
+            collect_wid: "1234".to_string(),
+            instance_wid: Some(2345),
+            school_task_wid: "4567".to_string(),
+            form: serde_json::json!([]),
+            ua_is_cpadaily: true,
+            latitude: 36.1,
+            longitude: 120.7,
+        };
+
+        let request = super::build_request(&form_data, &user, &PlainEncryptor).unwrap();
+        assert_eq!(request.user_id, "alice");
+        assert_eq!(request.device_id, "0123456789abcdef");
+        assert_eq!(request.version, "first_v2");
+        let body = PlainEncryptor
+            .decrypt(&request.body_string, KeyType::F)
+            .unwrap();
+        assert_eq!(
+            serde_json::from_str::<FormContentForSubmit>(&body).unwrap(),
+            form_data
+        );
+    }
+
+    #[test]
+    fn test_opens_in() {
+        let form = |start_time: &str| -> CollectorFormInstance {
+            serde_json::from_value(serde_json::json!({
+                "wid": "1234", "instanceWid": 2345, "formWid": "3456", "priority": "4",
+                "subject": "test", "content": "", "senderUserName": "test",
+                "createTime": "2021-11-08 00:16", "startTime": start_time,
+                "endTime": "2021-11-08 23:59", "currentTime": "2021-11-08 05:30:00",
+                "isHandled": 0, "isRead": 0,
+            }))
+            .unwrap()
+        };
+        assert_eq!(
+            super::opens_in(&form("2021-11-08 06:00")),
+            Some(std::time::Duration::from_secs(30 * 60))
+        );
+        assert_eq!(super::opens_in(&form("2021-11-08 05:00")), None);
+        assert_eq!(super::opens_in(&form("soon")), None);
+    }
+
+    #[test]
+    fn test_parse_responses() {
+        use reqwest::StatusCode;
+
+        let empty = r#"{"code":"0","message":"SUCCESS","datas":{"totalSize":0,"pageSize":20,"pageNumber":1,"rows":[]}}"#;
+        assert!(super::parse_form_list(StatusCode::OK, empty)
+            .unwrap()
+            .is_empty());
+        assert!(super::parse_form_list(StatusCode::NOT_FOUND, "").is_err());
+        assert!(super::parse_form_list(StatusCode::BAD_GATEWAY, "").is_err());
+
+        assert!(super::parse_session_valid(StatusCode::OK, empty).unwrap());
+        assert!(!super::parse_session_valid(StatusCode::OK, "<html></html>").unwrap());
+        assert!(!super::parse_session_valid(StatusCode::FORBIDDEN, "").unwrap());
+        assert!(super::parse_session_valid(StatusCode::BAD_GATEWAY, "").is_err());
+
+        assert!(super::parse_submit(StatusCode::OK, r#"{"code":"0","message":"SUCCESS"}"#).is_ok());
+        let err =
+            super::parse_submit(StatusCode::OK, r#"{"code":"1","message":"closed"}"#).unwrap_err();
+        assert_eq!(err.to_string(), "Form submit rejected: closed");
+        assert!(super::parse_submit(StatusCode::OK, "<html></html>").is_err());
+    }
 }
@@ -0,0 +1,92 @@
+// async counterparts of the collector calls, taking a client from
+// cpdaily::client::new_async
+use anyhow::Result;
+use reqwest::Client;
+use serde_json::Value;
+
+use super::structs::*;
+use super::{
+    build_request, form_detail_request, form_fields_request, form_list_request, parse_form_list,
+    parse_session_valid, parse_submit, FORM_DETAIL_PATH, FORM_FIELDS_PATH, FORM_LIST_PATH,
+    SUBMIT_FORM_PATH,
+};
+use crate::config::User;
+use crate::cpdaily::crypto::traits::first_v2::FirstV2;
+
+pub async fn get_form_list(
+    session: &Client,
+    base_url: &str,
+    page_size: u32,
+    page_number: u32,
+) -> Result<Vec<CollectorFormInstance>> {
+    let result = session
+        .post(format!("{}{}", base_url, FORM_LIST_PATH))
+        .json(&form_list_request(page_size, page_number))
+        .send()
+        .await?;
+    let status = result.status();
+    parse_form_list(status, &result.text().await?)
+}
+
+pub async fn is_session_valid(session: &Client, base_url: &str) -> Result<bool> {
+    let result = session
+        .post(format!("{}{}", base_url, FORM_LIST_PATH))
+        .json(&form_list_request(1, 1))
+        .send()
+        .await?;
+    let status = result.status();
+    parse_session_valid(status, &result.text().await?)
+}
+
+pub async fn get_form_detail(
+    session: &Client,
+    base_url: &str,
+    wid: &str,
+    instance_wid: Option<i64>,
+) -> Result<FormDetail> {
+    let result: CounselorResponse<FormDetail> = session
+        .post(format!("{}{}", base_url, FORM_DETAIL_PATH))
+        .json(&form_detail_request(wid, instance_wid))
+        .send()
+        .await?
+        .json()
+        .await?;
+    Ok(result.datas)
+}
+
+pub async fn get_form_fields(
+    session: &Client,
+    base_url: &str,
+    wid: &str,
+    form_wid: &str,
+    page_size: u32,
+    page_number: u32,
+) -> Result<Vec<Value>> {
+    let result: CounselorResponse<CounselorPaginator<Value>> = session
+        .post(format!("{}{}", base_url, FORM_FIELDS_PATH))
+        .json(&form_fields_request(wid, form_wid, page_size, page_number))
+        .send()
+        .await?
+        .json()
+        .await?;
+    Ok(result.datas.rows)
+}
+
+// the encryptor is only used before the first await, so a blocking provider
+// such as first_v2::Local is fine here
+pub async fn submit_form(
+    session: &Client,
+    base_url: &str,
+    form_data: &FormContentForSubmit,
+    user: &User,
+    encryptor: &dyn FirstV2,
+) -> Result<()> {
+    let payload = build_request(form_data, user, encryptor)?;
+    let result = session
+        .post(format!("{}{}", base_url, SUBMIT_FORM_PATH))
+        .json(&payload)
+        .send()
+        .await?;
+    let status = result.status();
+    parse_submit(status, &result.text().await?)
+}
@@ -0,0 +1,338 @@
+pub mod crypto;
+pub mod daemon;
+pub mod forms;
+mod pool;
+pub mod run;
+pub mod tenants;
+
+use std::{collections::BTreeMap, path::PathBuf, sync::Arc};
+
+use anyhow::{anyhow, bail, Context};
+use reqwest::blocking::Client;
+use serde_json::json;
+use url::Url;
+
+use crate::actions;
+use crate::config::{self, Config, User};
+use crate::cpdaily::{self, client, session, structs::tenants::Tenant};
+use crate::logger;
+
+pub fn load_config(path: &str) -> anyhow::Result<Config> {
+    let config = config::load_config(path)?;
+
+    logger::log(sentry::Breadcrumb {
+        category: Some("config".to_string()),
+        message: Some("loaded config file".to_string()),
+        data: {
+            let mut bt = BTreeMap::new();
+            for u in &config.users {
+                // only who they are, no address, location or answers
+                bt.insert(
+                    format!("user-{}-{}", &u.school, &u.username),
+                    json!({
+                        "school": u.school,
+                        "username": u.username,
+                    }),
+                );
+            }
+            bt
+        },
+        level: sentry::Level::Info,
+        ..Default::default()
+    });
+
+    Ok(config)
+}
+
+// `--user school:username`, all users when none is given
+pub fn select_users<'a>(users: &'a [User], selectors: &[String]) -> anyhow::Result<Vec<&'a User>> {
+    if selectors.is_empty() {
+        return Ok(users.iter().collect());
+    }
+    let mut selected: Vec<&User> = vec![];
+    for selector in selectors {
+        let (school, username) = selector
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected --user school:username, got \"{}\"", selector))?;
+        let user = users
+            .iter()
+            .find(|u| u.school == school && u.username == username)
+            .ok_or_else(|| anyhow!("No user {} in the config", selector))?;
+        if !selected.iter().any(|u| std::ptr::eq(*u, user)) {
+            selected.push(user);
+        }
+    }
+    Ok(selected)
+}
+
+// the selected users, with the tracked app version applied
+pub fn prepare_users(config: &Config, selectors: &[String]) -> anyhow::Result<Vec<User>> {
+    let selected = select_users(&config.users, selectors)?;
+
+    let tracked_app_version = match config.app_version.resolve() {
+        Ok(version) => {
+            if let Some(version) = &version {
+                logger::log(sentry::Breadcrumb {
+                    category: Some("config".to_string()),
+                    message: Some(format!("use app version {}", version)),
+                    level: sentry::Level::Info,
+                    ..Default::default()
+                });
+            }
+            version
+        }
+        Err(err) => {
+            logger::log(sentry::Breadcrumb {
+                category: Some("config".to_string()),
+                message: Some(format!(
+                    "failed to find the current app version, keep the configured ones: {}",
+                    err
+                )),
+                level: sentry::Level::Warning,
+                ..Default::default()
+            });
+            None
+        }
+    };
+
+    Ok(selected
+        .into_iter()
+        .map(|user| user.with_app_version(tracked_app_version.as_deref()))
+        .collect())
+}
+
+pub struct UserSession {
+    pub client: Client,
+    pub base_url: String,
+    cookie_jar: Arc<session::CookieJar>,
+    session_path: Option<PathBuf>,
+}
+
+impl UserSession {
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(path) = &self.session_path {
+            self.cookie_jar.save(path)?;
+        }
+        Ok(())
+    }
+}
+
+// log the user in, reusing the saved session when `reuse` is set and it is
+// still valid
+pub fn open_session(
+    user: &User,
+    tenant_list: &[Tenant],
+    session_dir: Option<&str>,
+    reuse: bool,
+) -> anyhow::Result<UserSession> {
+    let session_path =
+        session_dir.map(|dir| session::get_session_path(dir, &user.school, &user.username));
+    let cookie_jar = Arc::new(
+        session_path
+            .as_ref()
+            .filter(|path| path.exists())
+            .and_then(|path| session::CookieJar::load(path).ok())
+            .unwrap_or_default(),
+    );
+    let client = client::new(user, cookie_jar.clone())?;
+    let tenant = cpdaily::match_school_from_tenant_list(tenant_list, &user.school)?;
+
+    logger::log(sentry::Breadcrumb {
+        category: Some("tenant_service".to_string()),
+        message: Some(format!(
+            "matched \"{}\" to tenant \"{}\"",
+            &user.school, &tenant.name
+        )),
+        level: sentry::Level::Info,
+        ..Default::default()
+    });
+
+    let tenant_detail = tenant.get_info()?;
+    let base_url = tenant_detail.get_url()?;
+
+    logger::log(sentry::Breadcrumb {
+        category: Some("bus".to_string()),
+        message: Some(format!("set base url to \"{}\"", &base_url)),
+        level: sentry::Level::Info,
+        ..Default::default()
+    });
+
+    let user_session = UserSession {
+        client,
+        base_url,
+        cookie_jar,
+        session_path,
+    };
+    let client = &user_session.client;
+    let base_url = &user_session.base_url;
+    let cookie_jar = &user_session.cookie_jar;
+
+    if let Some(mod_auth_cas) = &user.mod_auth_cas {
+        // the cookie was exported from the app, never fall back to logging in
+        cookie_jar.import_mod_auth_cas(mod_auth_cas, &Url::parse(base_url)?);
+
+        // a network failure says nothing about the cookie
+        let valid = actions::counselor_form_fill::is_session_valid(client, base_url)
+            .context("Failed to check the imported MOD_AUTH_CAS")?;
+        if !valid {
+            bail!(
+                "Imported MOD_AUTH_CAS of {}:{} has expired, please export a new one",
+                &user.school,
+                &user.username
+            );
+        }
+
+        logger::log(sentry::Breadcrumb {
+            category: Some("login".to_string()),
+            message: Some("use imported MOD_AUTH_CAS".to_string()),
+            level: sentry::Level::Info,
+            ..Default::default()
+        });
+        return Ok(user_session);
+    }
+
+    if reuse
+        && !cookie_jar.is_empty()
+        && actions::counselor_form_fill::is_session_valid(client, base_url).unwrap_or(false)
+    {
+        logger::log(sentry::Breadcrumb {
+            category: Some("login".to_string()),
+            message: Some("reuse saved session".to_string()),
+            level: sentry::Level::Info,
+            ..Default::default()
+        });
+        return Ok(user_session);
+    }
+
+    cookie_jar.clear();
+
+    let login_options = user.get_login_options();
+
+    logger::log(sentry::Breadcrumb {
+        category: Some("login".to_string()),
+        message: Some(format!(
+            "use captcha solver {}",
+            login_options.captcha_solver.get_type()
+        )),
+        level: sentry::Level::Info,
+        ..Default::default()
+    });
+
+    if let Some(sms) = &login_options.sms {
+        logger::log(sentry::Breadcrumb {
+            category: Some("login".to_string()),
+            message: Some(format!(
+                "use sms login with code reader {}",
+                sms.code_reader.get_type()
+            )),
+            level: sentry::Level::Info,
+            ..Default::default()
+        });
+    }
+
+    let login_provider = match &user.login {
+        Some(login) => login.create_login(login_options)?,
+        None => tenant.create_login(login_options)?,
+    };
+
+    logger::log(sentry::Breadcrumb {
+        category: Some("login".to_string()),
+        message: Some(format!("use login provider {}", login_provider.get_type())),
+        level: sentry::Level::Info,
+        ..Default::default()
+    });
+
+    // the provider retries what is worth retrying under login_retry
+    login_provider
+        .login(client, &user.username, user.password.as_str()?)
+        .map_err(|err| {
+            err.context(format!(
+                "Failed to log in {}:{}",
+                &user.school, &user.username
+            ))
+        })?;
+
+    logger::log(sentry::Breadcrumb {
+        category: Some("login".to_string()),
+        message: Some("logged in".to_string()),
+        level: sentry::Level::Info,
+        ..Default::default()
+    });
+
+    user_session.save()?;
+    Ok(user_session)
+}
+
+pub fn login_test(config_path: &str, selectors: &[String]) -> anyhow::Result<()> {
+    let config = load_config(config_path)?;
+    let users = prepare_users(&config, selectors)?;
+    let tenant_list = cpdaily::get_all_tenants()?;
+
+    let mut failed = 0;
+    for user in &users {
+        // always log in again, a reused session proves nothing
+        match open_session(user, &tenant_list, config.session_dir.as_deref(), false) {
+            Ok(_) => println!("{}:{}: ok", &user.school, &user.username),
+            Err(err) => {
+                failed += 1;
+                println!("{}:{}: {:#}", &user.school, &user.username, err);
+            }
+        }
+    }
+    if failed > 0 {
+        bail!("{} of {} user(s) failed to log in", failed, users.len());
+    }
+    Ok(())
+}
+
+pub fn validate_config(path: &str) -> anyhow::Result<()> {
+    let diagnostics = config::validate::validate(path);
+    for diagnostic in &diagnostics {
+        println!("{}", diagnostic);
+    }
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.level == config::validate::Level::Error)
+        .count();
+    if errors > 0 {
+        return Err(anyhow!("{} error(s) found in {}", errors, path));
+    }
+    println!("{} is valid", path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::select_users;
+    use crate::config::test_user as user;
+
+    #[test]
+    fn test_select_users() {
+        let users = vec![
+            user("10000", "alice"),
+            user("10000", "bob"),
+            user("10001", "alice"),
+        ];
+        assert_eq!(select_users(&users, &[]).unwrap().len(), 3);
+
+        let selected = select_users(
+            &users,
+            &[
+                "10001:alice".to_string(),
+                "10000:bob".to_string(),
+                "10001:alice".to_string(),
+            ],
+        )
+        .unwrap();
+        assert_eq!(
+            selected
+                .iter()
+                .map(|u| format!("{}:{}", u.school, u.username))
+                .collect::<Vec<_>>(),
+            vec!["10001:alice", "10000:bob"]
+        );
+
+        assert!(select_users(&users, &["10000:carol".to_string()]).is_err());
+        assert!(select_users(&users, &["alice".to_string()]).is_err());
+    }
+}
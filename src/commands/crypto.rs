@@ -0,0 +1,22 @@
+use anyhow::bail;
+
+use crate::cpdaily::crypto::{
+    providers::first_v2,
+    traits::first_v2::{FirstV2, KeyType},
+};
+
+fn parse_key_type(key: &str) -> anyhow::Result<KeyType> {
+    match key {
+        "c" | "C" => Ok(KeyType::C),
+        "f" | "F" => Ok(KeyType::F),
+        _ => bail!("Unknown key \"{}\", expected c or f", key),
+    }
+}
+
+// decrypt a captured first_v2 body, like the bodyString of a form submit
+pub fn decrypt(text: &str, key: &str) -> anyhow::Result<()> {
+    let key_type = parse_key_type(key)?;
+    let provider = first_v2::Local::new();
+    println!("{}", provider.decrypt(text.trim(), key_type)?);
+    Ok(())
+}
@@ -0,0 +1,311 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Duration,
+};
+
+use anyhow::{bail, Context};
+use chrono::{DateTime, Local};
+use rand::Rng;
+
+use crate::config::{Action, Concurrency, Schedule, User};
+use crate::cpdaily::{self, crypto::providers::first_v2};
+use crate::logger;
+
+use super::pool::run_bounded;
+use super::run::{concurrency, format_summary, run_user, school_key, Outcome, RunOptions};
+use super::{load_config, prepare_users};
+
+// a user with only the actions that share one schedule
+struct Job {
+    user: User,
+    schedule: Schedule,
+    cron: cron::Schedule,
+    next: Option<DateTime<Local>>,
+    // extra runs for forms that open before the next scheduled one
+    extra: Vec<DateTime<Local>>,
+}
+
+struct Loaded {
+    concurrency: Concurrency,
+    session_dir: Option<String>,
+    jobs: Vec<Job>,
+}
+
+fn next_run(cron: &cron::Schedule, jitter: u64, after: DateTime<Local>) -> Option<DateTime<Local>> {
+    let next = cron.after(&after).next()?;
+    Some(with_jitter(next, jitter))
+}
+
+fn with_jitter(time: DateTime<Local>, jitter: u64) -> DateTime<Local> {
+    if jitter == 0 {
+        return time;
+    }
+    time + chrono::Duration::seconds(rand::thread_rng().gen_range(0..=jitter) as i64)
+}
+
+impl Job {
+    fn due(&self) -> Option<DateTime<Local>> {
+        self.next
+            .into_iter()
+            .chain(self.extra.iter().copied())
+            .min()
+    }
+
+    fn finish(&mut self, now: DateTime<Local>, outcomes: &[Outcome]) {
+        if self.next.is_some_and(|next| next <= now) {
+            self.next = next_run(&self.cron, self.schedule.jitter, now);
+        }
+        // every run sees all the forms that are not open yet, so the
+        // latest one replaces what the earlier runs found
+        self.extra.clear();
+        if self.schedule.form_windows {
+            for opens_in in outcomes.iter().flat_map(|o| &o.stats.opens_in) {
+                let opens_at = now + chrono::Duration::from_std(*opens_in).unwrap();
+                let at = with_jitter(opens_at, self.schedule.jitter);
+                if self.next.is_none_or(|next| at < next) {
+                    self.extra.push(at);
+                }
+            }
+        }
+    }
+}
+
+fn action_schedule(action: &Action) -> &Option<Schedule> {
+    match action {
+        Action::CounselorFormFill(form_fill) => &form_fill.schedule,
+    }
+}
+
+fn build_jobs(users: Vec<User>, now: DateTime<Local>) -> anyhow::Result<Vec<Job>> {
+    let mut jobs = vec![];
+    for user in users {
+        let mut groups: Vec<(Schedule, Vec<Action>)> = vec![];
+        for action in &user.actions {
+            let schedule = match action_schedule(action).as_ref().or(user.schedule.as_ref()) {
+                Some(schedule) => schedule,
+                None => {
+                    logger::log(sentry::Breadcrumb {
+                        category: Some("daemon".to_string()),
+                        message: Some(format!(
+                            "{}:{} has an action without a schedule, it never runs",
+                            &user.school, &user.username
+                        )),
+                        level: sentry::Level::Warning,
+                        ..Default::default()
+                    });
+                    continue;
+                }
+            };
+            match groups.iter_mut().find(|(s, _)| s == schedule) {
+                Some((_, actions)) => actions.push(action.clone()),
+                None => groups.push((schedule.clone(), vec![action.clone()])),
+            }
+        }
+
+        for (schedule, actions) in groups {
+            let cron = schedule
+                .parse_cron()
+                .with_context(|| format!("Schedule of {}:{}", &user.school, &user.username))?;
+            jobs.push(Job {
+                user: User {
+                    actions,
+                    ..user.clone()
+                },
+                next: next_run(&cron, schedule.jitter, now),
+                schedule,
+                cron,
+                extra: vec![],
+            });
+        }
+    }
+    Ok(jobs)
+}
+
+fn load(config_path: &str, selectors: &[String], options: &RunOptions) -> anyhow::Result<Loaded> {
+    let config = load_config(config_path)?;
+    let users = prepare_users(&config, selectors)?;
+    let jobs = build_jobs(users, Local::now())?;
+    for job in &jobs {
+        logger::log(sentry::Breadcrumb {
+            category: Some("daemon".to_string()),
+            message: Some(format!(
+                "scheduled {}:{} at \"{}\", next run {}",
+                &job.user.school,
+                &job.user.username,
+                &job.schedule.cron,
+                job.next
+                    .map_or("never".to_string(), |next| next.to_rfc3339())
+            )),
+            level: sentry::Level::Info,
+            ..Default::default()
+        });
+    }
+    Ok(Loaded {
+        concurrency: concurrency(&config, options),
+        session_dir: config.session_dir,
+        jobs,
+    })
+}
+
+pub fn daemon(config_path: &str, selectors: &[String], options: RunOptions) -> anyhow::Result<()> {
+    let reload = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload.clone())?;
+
+    let mut loaded = load(config_path, selectors, &options)?;
+    if loaded.jobs.is_empty() {
+        bail!("No user or action has a schedule");
+    }
+
+    // Initialize crypto providers
+    let first_v2_provider = first_v2::Local::new();
+
+    // Fetch tenant list
+    let tenant_list = cpdaily::get_all_tenants()?;
+
+    loop {
+        if reload.swap(false, Ordering::Relaxed) {
+            match load(config_path, selectors, &options) {
+                Ok(reloaded) => {
+                    logger::log(sentry::Breadcrumb {
+                        category: Some("daemon".to_string()),
+                        message: Some(format!("reloaded config, {} job(s)", reloaded.jobs.len())),
+                        level: sentry::Level::Info,
+                        ..Default::default()
+                    });
+                    loaded = reloaded;
+                }
+                Err(err) => logger::log(sentry::Breadcrumb {
+                    category: Some("daemon".to_string()),
+                    message: Some(format!(
+                        "failed to reload config, keep the old one: {:#}",
+                        err
+                    )),
+                    level: sentry::Level::Error,
+                    ..Default::default()
+                }),
+            }
+        }
+
+        // wake up every second to notice SIGHUP
+        let now = Local::now();
+        if !loaded
+            .jobs
+            .iter()
+            .any(|job| job.due().is_some_and(|due| due <= now))
+        {
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+
+        // the batch blocks the loop, jobs due meanwhile and SIGHUP wait for it
+        let due: Vec<usize> = (0..loaded.jobs.len())
+            .filter(|&i| loaded.jobs[i].due().is_some_and(|due| due <= now))
+            .collect();
+        let results = run_bounded(
+            &due,
+            &loaded.concurrency,
+            |&i| school_key(&tenant_list, &loaded.jobs[i].user),
+            |&i| {
+                let job = &loaded.jobs[i];
+                logger::log(sentry::Breadcrumb {
+                    category: Some("daemon".to_string()),
+                    message: Some(format!(
+                        "start scheduled run of {}:{}",
+                        &job.user.school, &job.user.username
+                    )),
+                    level: sentry::Level::Info,
+                    ..Default::default()
+                });
+
+                run_user(
+                    &job.user,
+                    &tenant_list,
+                    loaded.session_dir.as_deref(),
+                    &first_v2_provider,
+                    options.dry_run,
+                )
+            },
+        );
+
+        let finished = Local::now();
+        for (i, outcomes) in due.into_iter().zip(results) {
+            print!("{}", format_summary(&outcomes));
+            loaded.jobs[i].finish(finished, &outcomes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{Local, TimeZone, Timelike};
+
+    use super::{build_jobs, Outcome};
+    use crate::actions::counselor_form_fill::FormFillStats;
+    use crate::config::{test_user, User};
+
+    fn user() -> User {
+        let mut user = test_user("10000", "alice");
+        user.schedule =
+            Some(serde_yaml::from_str("cron: \"30 7 * * *\"\nform_windows: true").unwrap());
+        user.actions = serde_yaml::from_str(
+            r#"- type: CounselorFormFill
+  force_submit: false
+  form_data: []
+- type: CounselorFormFill
+  force_submit: true
+  form_data: []
+  schedule:
+    cron: "0 0 20 * * *"
+"#,
+        )
+        .unwrap();
+        user
+    }
+
+    #[test]
+    fn test_build_jobs() {
+        let now = Local.ymd(2021, 11, 8).and_hms(6, 0, 0);
+        let jobs = build_jobs(vec![user()], now).unwrap();
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].user.actions.len(), 1);
+        assert_eq!(jobs[0].next, Some(Local.ymd(2021, 11, 8).and_hms(7, 30, 0)));
+        assert_eq!(jobs[1].next, Some(Local.ymd(2021, 11, 8).and_hms(20, 0, 0)));
+
+        let mut no_schedule = user();
+        no_schedule.schedule = None;
+        assert_eq!(build_jobs(vec![no_schedule], now).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_finish() {
+        let now = Local.ymd(2021, 11, 8).and_hms(7, 30, 0);
+        let mut job = build_jobs(vec![user()], now - chrono::Duration::minutes(1))
+            .unwrap()
+            .remove(0);
+        assert_eq!(job.due(), Some(now));
+
+        let outcome = |opens_in: Vec<Duration>| Outcome {
+            user: "10000:alice".to_string(),
+            action: "CounselorFormFill".to_string(),
+            stats: FormFillStats {
+                opens_in,
+                ..Default::default()
+            },
+            error: None,
+        };
+        job.finish(now, &[outcome(vec![Duration::from_secs(30 * 60)])]);
+        assert_eq!(job.next, Some(now + chrono::Duration::days(1)));
+        assert_eq!(job.due().unwrap().hour(), 8);
+
+        // the extra run found the form open, back to the schedule
+        job.finish(now + chrono::Duration::minutes(30), &[outcome(vec![])]);
+        assert_eq!(job.due(), Some(now + chrono::Duration::days(1)));
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::anyhow;
+use serde_json::Value;
+
+use crate::actions::counselor_form_fill;
+use crate::cpdaily;
+
+use super::{load_config, open_session, prepare_users, UserSession};
+
+// log in each selected user and hand over its session
+fn for_each_session(
+    config_path: &str,
+    selectors: &[String],
+    mut f: impl FnMut(&UserSession) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let config = load_config(config_path)?;
+    let users = prepare_users(&config, selectors)?;
+    let tenant_list = cpdaily::get_all_tenants()?;
+
+    for user in &users {
+        if users.len() > 1 {
+            println!("== {}:{}", &user.school, &user.username);
+        }
+        let session = open_session(user, &tenant_list, config.session_dir.as_deref(), true)?;
+        f(&session)?;
+        session.save()?;
+    }
+    Ok(())
+}
+
+pub fn list(config_path: &str, selectors: &[String]) -> anyhow::Result<()> {
+    for_each_session(config_path, selectors, |session| {
+        let forms = counselor_form_fill::get_form_list(&session.client, &session.base_url, 20, 1)?;
+        if forms.is_empty() {
+            println!("No forms");
+        }
+        for form in forms {
+            println!(
+                "{}\t{}\t{}\t{}",
+                form.wid,
+                if form.is_handled == 1 {
+                    "filled"
+                } else {
+                    "open"
+                },
+                form.end_time,
+                form.subject
+            );
+        }
+        Ok(())
+    })
+}
+
+fn field_str<'a>(field: &'a Value, key: &str) -> &'a str {
+    field.get(key).and_then(|v| v.as_str()).unwrap_or("")
+}
+
+pub fn show(config_path: &str, selectors: &[String], wid: &str) -> anyhow::Result<()> {
+    for_each_session(config_path, selectors, |session| {
+        let forms = counselor_form_fill::get_form_list(&session.client, &session.base_url, 20, 1)?;
+        let form = forms
+            .iter()
+            .find(|form| form.wid == wid)
+            .ok_or_else(|| anyhow!("No form {} in the form list", wid))?;
+        let detail = counselor_form_fill::get_form_detail(
+            &session.client,
+            &session.base_url,
+            &form.wid,
+            form.instance_wid,
+        )?;
+        let fields = counselor_form_fill::get_form_fields(
+            &session.client,
+            &session.base_url,
+            &form.wid,
+            &form.form_wid,
+            100,
+            1,
+        )?;
+
+        println!("[{}] {}", form.wid, form.subject);
+        println!(
+            "from {}, {} - {}",
+            form.sender_user_name, form.start_time, form.end_time
+        );
+        if !detail.form.form_content.is_empty() {
+            println!("{}", detail.form.form_content);
+        }
+        for (i, field) in fields.iter().enumerate() {
+            let required = field
+                .get("isRequired")
+                .and_then(|v| v.as_bool())
+                .unwrap_or(false);
+            println!(
+                "{}. {} (type {}{})",
+                i + 1,
+                field_str(field, "title"),
+                field_str(field, "fieldType"),
+                if required { ", required" } else { "" }
+            );
+            if let Some(items) = field.get("fieldItems").and_then(|v| v.as_array()) {
+                for item in items {
+                    println!("   - {}", field_str(item, "content"));
+                }
+            }
+        }
+        Ok(())
+    })
+}
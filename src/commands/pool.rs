@@ -0,0 +1,203 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Condvar, Mutex},
+    thread,
+};
+
+use crate::config::Concurrency;
+
+struct State {
+    pending: VecDeque<usize>,
+    running: HashMap<String, usize>,
+}
+
+struct Release<'a> {
+    state: &'a Mutex<State>,
+    wake: &'a Condvar,
+    key: String,
+}
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        let mut state = self
+            .state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(running) = state.running.get_mut(&self.key) {
+            *running -= 1;
+        }
+        drop(state);
+        self.wake.notify_all();
+    }
+}
+
+// run `f` over `items` on up to `limits.users` threads, with no more than
+// `limits.per_school` of the items sharing a `key` at once; the results keep
+// the order of the items
+pub fn run_bounded<T, R>(
+    items: &[T],
+    limits: &Concurrency,
+    key: impl Fn(&T) -> String + Sync,
+    f: impl Fn(&T) -> R + Sync,
+) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+{
+    let state = Mutex::new(State {
+        pending: (0..items.len()).collect(),
+        running: HashMap::new(),
+    });
+    let wake = Condvar::new();
+    let results: Mutex<Vec<Option<R>>> = Mutex::new(items.iter().map(|_| None).collect());
+    let workers = limits.users.max(1).min(items.len());
+
+    let next = || -> Option<(usize, String)> {
+        let mut state = state.lock().unwrap();
+        loop {
+            if state.pending.is_empty() {
+                return None;
+            }
+            let free = state.pending.iter().position(|&i| {
+                let running = state.running.get(&key(&items[i])).copied().unwrap_or(0);
+                limits.per_school.is_none_or(|limit| running < limit.max(1))
+            });
+            match free {
+                Some(position) => {
+                    let index = state.pending.remove(position).unwrap();
+                    let key = key(&items[index]);
+                    *state.running.entry(key.clone()).or_default() += 1;
+                    return Some((index, key));
+                }
+                None => state = wake.wait(state).unwrap(),
+            }
+        }
+    };
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                while let Some((index, key)) = next() {
+                    // frees the slot even if `f` panics, or the others
+                    // waiting on the key would wait forever
+                    let _release = Release {
+                        state: &state,
+                        wake: &wake,
+                        key,
+                    };
+                    let result = f(&items[index]);
+                    results
+                        .lock()
+                        .unwrap_or_else(|poisoned| poisoned.into_inner())[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .into_iter()
+        .map(Option::unwrap)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::Mutex,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::run_bounded;
+    use crate::config::Concurrency;
+
+    #[test]
+    fn test_run_bounded() {
+        let items: Vec<(&str, u32)> = vec![("a", 1), ("a", 2), ("a", 3), ("b", 4), ("b", 5)];
+        let running: Mutex<(usize, HashMap<&str, usize>)> = Mutex::new((0, HashMap::new()));
+        let peak: Mutex<(usize, HashMap<&str, usize>)> = Mutex::new((0, HashMap::new()));
+        let limits = Concurrency {
+            users: 3,
+            per_school: Some(2),
+        };
+
+        let results = run_bounded(
+            &items,
+            &limits,
+            |(school, _)| school.to_string(),
+            |(school, n)| {
+                {
+                    let mut running = running.lock().unwrap();
+                    running.0 += 1;
+                    *running.1.entry(school).or_default() += 1;
+                    let mut peak = peak.lock().unwrap();
+                    peak.0 = peak.0.max(running.0);
+                    let school_peak = peak.1.entry(school).or_default();
+                    *school_peak = (*school_peak).max(running.1[school]);
+                }
+                thread::sleep(Duration::from_millis(50));
+                let mut running = running.lock().unwrap();
+                running.0 -= 1;
+                *running.1.get_mut(school).unwrap() -= 1;
+                n * 10
+            },
+        );
+
+        assert_eq!(results, vec![10, 20, 30, 40, 50]);
+        let peak = peak.into_inner().unwrap();
+        assert_eq!(peak.0, 3);
+        assert_eq!(peak.1["a"], 2);
+        assert!(peak.1["b"] <= 2);
+    }
+
+    #[test]
+    fn test_run_bounded_sequential() {
+        let started = Instant::now();
+        let results = run_bounded(
+            &[1, 2, 3],
+            &Concurrency::default(),
+            |_| String::new(),
+            |n| {
+                thread::sleep(Duration::from_millis(20));
+                *n
+            },
+        );
+        assert_eq!(results, vec![1, 2, 3]);
+        assert!(started.elapsed() >= Duration::from_millis(60));
+        assert!(run_bounded(
+            &[] as &[u32],
+            &Concurrency::default(),
+            |_| String::new(),
+            |n| *n
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn test_run_bounded_panic() {
+        let done = Mutex::new(vec![]);
+        let limits = Concurrency {
+            users: 2,
+            per_school: Some(1),
+        };
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            run_bounded(
+                &[1, 2, 3],
+                &limits,
+                |_| "a".to_string(),
+                |&n| {
+                    if n == 1 {
+                        panic!("boom");
+                    }
+                    done.lock().unwrap().push(n);
+                },
+            )
+        }));
+        // the panic still surfaces, but only after the rest of the school ran
+        assert!(result.is_err());
+        assert_eq!(done.into_inner().unwrap(), vec![2, 3]);
+    }
+}
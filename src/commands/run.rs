@@ -0,0 +1,258 @@
+use std::panic::{self, AssertUnwindSafe};
+
+use anyhow::{anyhow, bail};
+
+use crate::actions::{self, counselor_form_fill::FormFillStats};
+use crate::config::{Action, Concurrency, Config, User};
+use crate::cpdaily::{self, crypto::providers::first_v2, structs::tenants::Tenant};
+use crate::logger;
+
+use super::pool::run_bounded;
+use super::{load_config, open_session, prepare_users};
+
+// one row of the summary, a user's login or one of its actions
+pub struct Outcome {
+    pub user: String,
+    pub action: String,
+    pub stats: FormFillStats,
+    pub error: Option<String>,
+}
+
+// a backstop only: what the server may get wrong is an error already, but a
+// bug deep in a provider or action should still fail the one user, not the
+// run, so release builds unwind
+fn isolate<T>(f: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| payload.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_string());
+            Err(anyhow!("Panicked: {}", message))
+        }
+    }
+}
+
+fn action_name(action: &Action) -> &'static str {
+    match action {
+        Action::CounselorFormFill(_) => "CounselorFormFill",
+    }
+}
+
+pub fn run_user(
+    user: &User,
+    tenant_list: &[Tenant],
+    session_dir: Option<&str>,
+    first_v2_provider: &first_v2::Local,
+    dry_run: bool,
+) -> Vec<Outcome> {
+    let name = format!("{}:{}", &user.school, &user.username);
+    let failed = |action: &str, err: anyhow::Error| {
+        logger::log(sentry::Breadcrumb {
+            category: Some("bus".to_string()),
+            message: Some(format!("{} of {} failed: {:#}", action, &name, err)),
+            level: sentry::Level::Error,
+            ..Default::default()
+        });
+        sentry::integrations::anyhow::capture_anyhow(&err);
+        Outcome {
+            user: name.clone(),
+            action: action.to_string(),
+            stats: FormFillStats::default(),
+            error: Some(format!("{:#}", err)),
+        }
+    };
+
+    let session = match isolate(|| open_session(user, tenant_list, session_dir, true)) {
+        Ok(session) => session,
+        Err(err) => return vec![failed("login", err)],
+    };
+
+    let mut outcomes = vec![];
+    for action in &user.actions {
+        let mut stats = FormFillStats::default();
+        let result = isolate(|| match action {
+            Action::CounselorFormFill(form_fill) => actions::counselor_form_fill::perform(
+                &session.client,
+                &session.base_url,
+                form_fill,
+                user,
+                first_v2_provider,
+                &mut stats,
+                dry_run,
+            ),
+        });
+        let mut outcome = match result {
+            Ok(()) => Outcome {
+                user: name.clone(),
+                action: action_name(action).to_string(),
+                stats: FormFillStats::default(),
+                error: None,
+            },
+            Err(err) => failed(action_name(action), err),
+        };
+        outcome.stats = stats;
+        outcomes.push(outcome);
+    }
+
+    if let Err(err) = session.save() {
+        outcomes.push(failed("save session", err));
+    }
+    outcomes
+}
+
+pub fn format_summary(outcomes: &[Outcome]) -> String {
+    let header = ["user", "action", "handled", "submitted", "skipped", "error"];
+    let rows: Vec<[String; 6]> = outcomes
+        .iter()
+        .map(|o| {
+            [
+                o.user.clone(),
+                o.action.clone(),
+                o.stats.handled.to_string(),
+                o.stats.submitted.to_string(),
+                o.stats.skipped.to_string(),
+                o.error.clone().unwrap_or_else(|| "-".to_string()),
+            ]
+        })
+        .collect();
+
+    let mut widths = header.map(|h| h.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut summary = String::new();
+    let header = header.map(|h| h.to_string());
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{}{}", cell, " ".repeat(width - cell.chars().count())))
+            .collect();
+        summary.push_str(cells.join("  ").trim_end());
+        summary.push('\n');
+    }
+    summary
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunOptions {
+    pub dry_run: bool,
+    // overrides concurrency.users of the config
+    pub jobs: Option<usize>,
+}
+
+pub fn concurrency(config: &Config, options: &RunOptions) -> Concurrency {
+    let mut concurrency = config.concurrency.clone();
+    if let Some(jobs) = options.jobs {
+        concurrency.users = jobs;
+    }
+    concurrency
+}
+
+// users are limited per tenant, however the config names their school
+pub fn school_key(tenant_list: &[Tenant], user: &User) -> String {
+    cpdaily::match_school_from_tenant_list(tenant_list, &user.school)
+        .map(|tenant| tenant.id.clone())
+        .unwrap_or_else(|_| user.school.clone())
+}
+
+pub fn run(config_path: &str, selectors: &[String], options: RunOptions) -> anyhow::Result<()> {
+    let config = load_config(config_path)?;
+    let users = prepare_users(&config, selectors)?;
+
+    // Initialize crypto providers
+    let first_v2_provider = first_v2::Local::new();
+
+    // Fetch tenant list
+    let tenant_list = cpdaily::get_all_tenants()?;
+
+    // For each user
+    let outcomes: Vec<Outcome> = run_bounded(
+        &users,
+        &concurrency(&config, &options),
+        |user| school_key(&tenant_list, user),
+        |user| {
+            logger::log(sentry::Breadcrumb {
+                category: Some("bus".to_string()),
+                message: Some(format!("start user {}:{}", &user.school, &user.username)),
+                level: sentry::Level::Info,
+                ..Default::default()
+            });
+
+            let outcomes = run_user(
+                user,
+                &tenant_list,
+                config.session_dir.as_deref(),
+                &first_v2_provider,
+                options.dry_run,
+            );
+
+            logger::log(sentry::Breadcrumb {
+                category: Some("bus".to_string()),
+                message: Some(format!("end user {}:{}", &user.school, &user.username)),
+                level: sentry::Level::Info,
+                ..Default::default()
+            });
+            outcomes
+        },
+    )
+    .into_iter()
+    .flatten()
+    .collect();
+
+    print!("{}", format_summary(&outcomes));
+
+    let failed = outcomes.iter().filter(|o| o.error.is_some()).count();
+    if failed > 0 {
+        bail!("{} of {} step(s) failed", failed, outcomes.len());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_summary, isolate, Outcome};
+    use crate::actions::counselor_form_fill::FormFillStats;
+
+    #[test]
+    fn test_isolate() {
+        assert_eq!(isolate(|| Ok(1)).unwrap(), 1);
+        let err = isolate::<()>(|| panic!("boom")).unwrap_err();
+        assert_eq!(err.to_string(), "Panicked: boom");
+    }
+
+    #[test]
+    fn test_format_summary() {
+        let outcomes = vec![
+            Outcome {
+                user: "10000:alice".to_string(),
+                action: "CounselorFormFill".to_string(),
+                stats: FormFillStats {
+                    handled: 2,
+                    submitted: 1,
+                    skipped: 1,
+                    ..Default::default()
+                },
+                error: None,
+            },
+            Outcome {
+                user: "10000:bob".to_string(),
+                action: "login".to_string(),
+                stats: FormFillStats::default(),
+                error: Some("Wrong password".to_string()),
+            },
+        ];
+        assert_eq!(
+            format_summary(&outcomes),
+            "user         action             handled  submitted  skipped  error\n\
+             10000:alice  CounselorFormFill  2        1          1        -\n\
+             10000:bob    login              0        0          0        Wrong password\n"
+        );
+    }
+}
@@ -0,0 +1,46 @@
+use anyhow::anyhow;
+
+use crate::cpdaily::{self, structs::tenants::Tenant};
+
+fn find<'a>(tenants: &'a [Tenant], query: &str) -> Vec<&'a Tenant> {
+    tenants
+        .iter()
+        .filter(|tenant| tenant.id == query || tenant.name.contains(query))
+        .collect()
+}
+
+pub fn search(query: &str) -> anyhow::Result<()> {
+    let tenants = cpdaily::get_all_tenants()?;
+    let matches = find(&tenants, query);
+    if matches.is_empty() {
+        return Err(anyhow!("No school matches \"{}\"", query));
+    }
+    for tenant in matches {
+        println!(
+            "{}\t{}\t{:?}",
+            tenant.id,
+            tenant.name,
+            tenant.get_login_type()
+        );
+    }
+    Ok(())
+}
+
+pub fn show(id: &str) -> anyhow::Result<()> {
+    let tenants = cpdaily::get_all_tenants()?;
+    let tenant = tenants
+        .iter()
+        .find(|tenant| tenant.id == id)
+        .ok_or_else(|| anyhow!("No school with id \"{}\"", id))?;
+    let detail = tenant.get_info()?;
+
+    println!("id:         {}", tenant.id);
+    println!("name:       {}", tenant.name);
+    println!("code:       {}", tenant.tenant_code);
+    println!("join type:  {}", tenant.join_type);
+    println!("login type: {:?}", tenant.get_login_type());
+    println!("ids url:    {}", tenant.ids_url);
+    println!("cas url:    {}", tenant.cas_login_url);
+    println!("base url:   {}", detail.get_url()?);
+    Ok(())
+}
@@ -1,20 +1,199 @@
-use crate::cpdaily::structs::extensions::Extensions;
+use crate::cpdaily::app_version;
+use crate::cpdaily::loginprovider::{
+    self,
+    captcha::{self, CaptchaSolver},
+    detect,
+    sms::{self, SmsLogin},
+    LoginOptions, LoginProvider, PasswordEncryption, RetryPolicy,
+};
+use crate::cpdaily::structs::{extensions::Extensions, tenants::LoginProviderType};
+use anyhow::{anyhow, bail, Context};
 use serde::{Deserialize, Serialize};
-use std::{fs::File, io::Read};
+use serde_yaml::Value;
+use std::{
+    collections::BTreeMap,
+    env, fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+pub mod device_profile;
+pub mod encryption;
+mod templates;
+pub mod validate;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     pub users: Vec<User>,
+    #[serde(default)]
+    pub session_dir: Option<String>,
+    // merged into every user, see templates::expand
+    #[serde(default)]
+    pub defaults: serde_yaml::Mapping,
+    #[serde(default)]
+    pub templates: BTreeMap<String, serde_yaml::Mapping>,
+    #[serde(default)]
+    pub app_version: AppVersionSource,
+    #[serde(default)]
+    pub concurrency: Concurrency,
+    // keyed by the school as the users write it, for schools whose login
+    // page needs otherwise; a user's own password_encryption wins
+    #[serde(default)]
+    pub password_encryption: BTreeMap<String, PasswordEncryption>,
+}
+
+// how many users are worked on at once, in total and per school
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Concurrency {
+    #[serde(default = "Concurrency::default_users")]
+    pub users: usize,
+    #[serde(default)]
+    pub per_school: Option<usize>,
+}
+
+impl Concurrency {
+    fn default_users() -> usize {
+        1
+    }
+}
+
+impl Default for Concurrency {
+    fn default() -> Self {
+        Concurrency {
+            users: Concurrency::default_users(),
+            per_school: None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct User {
     pub school: String,
     pub username: String,
-    pub password: String,
+    #[serde(default)]
+    pub password: Password,
     pub address: String,
     pub actions: Vec<Action>,
     pub device_info: DeviceInfo,
+    // fills in device_info, see device_profile::apply
+    #[serde(default)]
+    pub device_profile: Option<String>,
+    #[serde(default)]
+    pub captcha: Captcha,
+    #[serde(default)]
+    pub login_retry: RetryPolicy,
+    #[serde(default)]
+    pub password_encryption: Option<PasswordEncryption>,
+    #[serde(default)]
+    pub login_mode: LoginMode,
+    #[serde(default)]
+    pub login: Option<LoginOverride>,
+    #[serde(default)]
+    pub mod_auth_cas: Option<String>,
+    // used instead of the version found through Config::app_version
+    #[serde(default)]
+    pub app_version_pin: Option<String>,
+    // when the daemon runs the actions without a schedule of their own
+    #[serde(default)]
+    pub schedule: Option<Schedule>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Schedule {
+    pub cron: String,
+    // seconds, each run starts up to this much later
+    #[serde(default)]
+    pub jitter: u64,
+    // run again when a listed form opens later on
+    #[serde(default)]
+    pub form_windows: bool,
+}
+
+impl Schedule {
+    pub fn parse_cron(&self) -> anyhow::Result<cron::Schedule> {
+        let invalid =
+            |err: &dyn fmt::Display| anyhow!("Invalid cron expression \"{}\": {}", self.cron, err);
+        // crontab lines have no seconds field
+        let fields: Vec<&str> = self.cron.split_whitespace().collect();
+        let expression = match fields[..] {
+            [minute, hour, day, month, weekday] => format!(
+                "0 {} {} {} {} {}",
+                minute,
+                hour,
+                day,
+                month,
+                crontab_weekdays(weekday).map_err(|err| invalid(&err))?
+            ),
+            _ => self.cron.clone(),
+        };
+        cron::Schedule::from_str(&expression).map_err(|err| invalid(&err))
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// crontab counts weekdays from Sunday = 0 (or 7), the cron crate from
+// Sunday = 1, so spell the days out in the crate's numbering
+fn crontab_weekdays(field: &str) -> anyhow::Result<String> {
+    if field == "*" {
+        return Ok(field.to_owned());
+    }
+    let day = |s: &str| -> anyhow::Result<u32> {
+        match s.parse::<u32>() {
+            Ok(n) if n <= 7 => Ok(n),
+            Ok(n) => bail!("day of week {} is not between 0 and 7", n),
+            Err(_) => WEEKDAYS
+                .iter()
+                .position(|d| d.eq_ignore_ascii_case(s))
+                .map(|i| i as u32)
+                .ok_or_else(|| anyhow!("unknown day of week \"{}\"", s)),
+        }
+    };
+
+    let mut days = std::collections::BTreeSet::new();
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (
+                range,
+                step.parse::<u32>()
+                    .ok()
+                    .filter(|step| *step > 0)
+                    .ok_or_else(|| anyhow!("invalid step \"{}\"", step))?,
+            ),
+            None => (item, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (0, 6),
+            Some((start, end)) => (day(start)?, day(end)?),
+            None if step > 1 => (day(range)?, 6),
+            None => (day(range)?, day(range)?),
+        };
+        if start > end {
+            bail!("day of week range \"{}\" runs backwards", range);
+        }
+        days.extend((start..=end).step_by(step as usize).map(|d| d % 7 + 1));
+    }
+    Ok(days
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join(","))
+}
+
+// either the password itself or where to read it from
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Password {
+    Plain(String),
+    Ref(PasswordRef),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum PasswordRef {
+    Env { name: String },
+    File { path: String },
+    Command { command: String },
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -29,22 +208,630 @@ pub struct DeviceInfo {
     pub user_agent: String,
 }
 
+// where the current app version comes from, Fixed keeps each user's own
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum AppVersionSource {
+    #[default]
+    Fixed,
+    Endpoint {
+        url: String,
+        // json pointer to the version, the whole body when left out
+        #[serde(default)]
+        pointer: Option<String>,
+    },
+    File {
+        path: String,
+    },
+}
+
+impl AppVersionSource {
+    pub fn resolve(&self) -> anyhow::Result<Option<String>> {
+        Ok(match self {
+            AppVersionSource::Fixed => None,
+            AppVersionSource::Endpoint { url, pointer } => {
+                Some(app_version::fetch(url, pointer.as_deref())?)
+            }
+            AppVersionSource::File { path } => Some(app_version::read_file(path)?),
+        })
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Action {
     CounselorFormFill(crate::actions::CounselorFormFillAction),
 }
 
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum Captcha {
+    #[default]
+    Fail,
+    Prompt {
+        #[serde(default = "default_captcha_path")]
+        path: String,
+    },
+    Command {
+        command: String,
+    },
+}
+
+// bypasses the tenant's ids_url and join_type
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoginOverride {
+    // detected from the url when left out
+    #[serde(default)]
+    pub provider: Option<LoginProviderType>,
+    pub url: String,
+    #[serde(default)]
+    pub service: Option<String>,
+}
+
+impl LoginOverride {
+    pub fn create_login(&self, options: LoginOptions) -> anyhow::Result<Box<dyn LoginProvider>> {
+        let url = self.url.trim_end_matches('/').to_owned();
+        let (login_type, url) = match self.provider {
+            None | Some(LoginProviderType::Unknown) => detect::detect(&url)?,
+            Some(login_type) => (login_type, url),
+        };
+        loginprovider::create(login_type, url, self.service.clone(), options)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum LoginMode {
+    #[default]
+    Password,
+    Sms {
+        mobile: String,
+        #[serde(default)]
+        code: SmsCode,
+    },
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum SmsCode {
+    #[default]
+    Prompt,
+    Command {
+        command: String,
+    },
+}
+
+impl Default for Password {
+    fn default() -> Self {
+        Password::Plain("".to_string())
+    }
+}
+
+// keep passwords out of logs and panic messages
+impl fmt::Debug for Password {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Password::Plain(_) => write!(f, "Plain(***)"),
+            Password::Ref(r) => write!(f, "Ref({:?})", r),
+        }
+    }
+}
+
+impl Password {
+    pub fn resolve(&self) -> anyhow::Result<String> {
+        match self {
+            Password::Plain(password) => Ok(password.clone()),
+            Password::Ref(PasswordRef::Env { name }) => {
+                env::var(name).with_context(|| format!("Failed to read password from ${}", name))
+            }
+            Password::Ref(PasswordRef::File { path }) => fs::read_to_string(path)
+                // secret files usually end with a newline
+                .map(|s| s.trim_end_matches(&['\r', '\n'][..]).to_owned())
+                .with_context(|| format!("Failed to read password from {}", path)),
+            Password::Ref(PasswordRef::Command { command }) => {
+                loginprovider::run_hook(command, &[], &[])
+                    .with_context(|| format!("Failed to read password from \"{}\"", command))
+            }
+        }
+    }
+
+    // only load_config resolves references, users from elsewhere need resolve
+    pub fn as_str(&self) -> anyhow::Result<&str> {
+        match self {
+            Password::Plain(password) => Ok(password),
+            Password::Ref(r) => Err(anyhow!("Password {:?} is not resolved", r)),
+        }
+    }
+}
+
+fn default_captcha_path() -> String {
+    "captcha.jpg".to_string()
+}
+
+pub fn parse_config(contents: &[u8]) -> anyhow::Result<Config> {
+    let mut value: Value = serde_yaml::from_slice(contents)?;
+    templates::expand(&mut value)?;
+    Ok(serde_yaml::from_value(value)?)
+}
+
+// a whole config is checked against the schema, the pieces of a config
+// directory only for syntax
+pub fn check_config(contents: &[u8]) -> anyhow::Result<()> {
+    let value: Value = serde_yaml::from_slice(contents)?;
+    if value.get("users").is_some() {
+        parse_config(contents)?;
+    }
+    Ok(())
+}
+
+fn read_config_file(path: &Path, passphrase: &mut Option<String>) -> anyhow::Result<Vec<u8>> {
+    let contents = fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    if !encryption::is_encrypted(&contents) {
+        return Ok(contents);
+    }
+    // every file of a config directory shares one passphrase
+    if passphrase.is_none() {
+        *passphrase = Some(encryption::get_passphrase(false)?);
+    }
+    encryption::decrypt(&contents, passphrase.as_ref().unwrap())
+        .with_context(|| format!("Failed to decrypt {}", path.display()))
+}
+
+fn list_config_dir(dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| {
+            path.is_file()
+                && matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("yml") | Some("yaml")
+                )
+        })
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+fn is_defaults_file(path: &Path) -> bool {
+    path.file_stem().and_then(|stem| stem.to_str()) == Some("_defaults")
+}
+
+// every *.yml in the directory holds one user or a list of users, while
+// _defaults.yml holds everything else (session_dir, defaults, templates)
+fn load_config_dir(dir: &Path) -> anyhow::Result<Config> {
+    let paths = list_config_dir(dir)?;
+    let mut passphrase = None;
+    let mut root = serde_yaml::Mapping::new();
+    let mut user_files = vec![];
+    for path in paths {
+        let contents = read_config_file(&path, &mut passphrase)?;
+        let value: Value = serde_yaml::from_slice(&contents)
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+        if is_defaults_file(&path) {
+            root = match value {
+                Value::Mapping(root) if root.contains_key(&Value::from("users")) => {
+                    bail!("{} cannot hold users", path.display())
+                }
+                Value::Mapping(root) => root,
+                Value::Null => serde_yaml::Mapping::new(),
+                _ => bail!("{} must be a mapping", path.display()),
+            };
+            continue;
+        }
+        let users = match value {
+            Value::Sequence(users) => users,
+            Value::Null => vec![],
+            user => vec![user],
+        };
+        user_files.push((path, users));
+    }
+
+    root.insert(Value::from("users"), Value::Sequence(vec![]));
+    let mut config: Config = serde_yaml::from_value(Value::Mapping(root.clone()))
+        .with_context(|| format!("Failed to parse {}", dir.join("_defaults.yml").display()))?;
+
+    let mut sources: Vec<PathBuf> = vec![];
+    for (path, users) in user_files {
+        let mut file_root = root.clone();
+        file_root.insert(Value::from("users"), Value::Sequence(users));
+        let mut value = Value::Mapping(file_root);
+        templates::expand(&mut value).with_context(|| format!("{}", path.display()))?;
+        let users: Vec<User> = serde_yaml::from_value(value["users"].clone())
+            .with_context(|| format!("Failed to parse {}", path.display()))?;
+
+        for user in users {
+            if let Some(i) = config
+                .users
+                .iter()
+                .position(|u| u.school == user.school && u.username == user.username)
+            {
+                bail!(
+                    "Duplicate user {}:{} in {} and {}",
+                    user.school,
+                    user.username,
+                    sources[i].display(),
+                    path.display()
+                );
+            }
+            config.users.push(user);
+            sources.push(path.clone());
+        }
+    }
+    Ok(config)
+}
+
 pub fn load_config(path: &str) -> anyhow::Result<Config> {
-    let mut file = File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
-    let config: Config = serde_yaml::from_str(&contents)?;
+    let path = Path::new(path);
+    let mut config = if path.is_dir() {
+        load_config_dir(path)?
+    } else {
+        parse_config(&read_config_file(path, &mut None)?)?
+    };
+    for user in &mut config.users {
+        let password = user.password.resolve().with_context(|| {
+            format!(
+                "Failed to load password of {}:{}",
+                user.school, user.username
+            )
+        })?;
+        // only SMS logins and imported cookies go without one
+        if password.is_empty()
+            && user.login_mode == LoginMode::Password
+            && user.mod_auth_cas.is_none()
+        {
+            bail!(
+                "{}:{} has no password, set one or use login_mode Sms",
+                user.school,
+                user.username
+            );
+        }
+        user.password = Password::Plain(password);
+        if user.password_encryption.is_none() {
+            user.password_encryption = config.password_encryption.get(&user.school).copied();
+        }
+    }
     Ok(config)
 }
 
+impl DeviceInfo {
+    pub fn set_app_version(&mut self, version: &str) {
+        self.app_version = version.to_owned();
+        self.user_agent = app_version::rewrite_user_agent(&self.user_agent, version);
+    }
+}
+
 impl User {
     pub fn get_cpdaily_extension(&self) -> Extensions {
         Extensions::from_user_id_and_deviceinfo(self.username.as_str(), &self.device_info)
     }
+
+    // the pinned version wins over the tracked one
+    pub fn with_app_version(&self, tracked: Option<&str>) -> User {
+        let mut user = self.clone();
+        if let Some(version) = self.app_version_pin.as_deref().or(tracked) {
+            user.device_info.set_app_version(version);
+        }
+        user
+    }
+
+    pub fn get_login_options(&self) -> LoginOptions {
+        LoginOptions {
+            captcha_solver: self.create_captcha_solver(),
+            retry: self.login_retry.clone(),
+            password_encryption: self.password_encryption.unwrap_or_default(),
+            sms: match &self.login_mode {
+                LoginMode::Password => None,
+                LoginMode::Sms { mobile, code } => Some(SmsLogin {
+                    mobile: mobile.clone(),
+                    code_reader: match code {
+                        SmsCode::Prompt => Box::new(sms::Prompt),
+                        SmsCode::Command { command } => Box::new(sms::Command {
+                            command: command.clone(),
+                        }),
+                    },
+                }),
+            },
+        }
+    }
+
+    fn create_captcha_solver(&self) -> Box<dyn CaptchaSolver> {
+        match &self.captcha {
+            Captcha::Fail => Box::new(captcha::Fail),
+            Captcha::Prompt { path } => Box::new(captcha::Prompt { path: path.clone() }),
+            Captcha::Command { command } => Box::new(captcha::Command {
+                command: command.clone(),
+            }),
+        }
+    }
+}
+
+// a complete user for tests, with a fixed device
+#[cfg(any(test, feature = "test-util"))]
+pub fn test_user(school: &str, username: &str) -> User {
+    serde_yaml::from_str(&format!(
+        "school: \"{}\"\nusername: {}\naddress: somewhere\ndevice_info:\n  model: MI 6\n  app_version: \"9.0.12\"\n  system_version: \"8.0.0\"\n  system_name: android\n  device_id: 0123456789abcdef\n  lat: 36.1\n  lon: 120.7\n  user_agent: ua\nactions: []\n",
+        school, username
+    ))
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{
+        load_config, parse_config, AppVersionSource, Password, PasswordEncryption, PasswordRef,
+        Schedule,
+    };
+
+    #[test]
+    fn test_password_deserialise() {
+        let password: Password = serde_yaml::from_str("hunter2").unwrap();
+        assert_eq!(password, Password::Plain("hunter2".to_string()));
+        let password: Password =
+            serde_yaml::from_str("{type: Env, name: CPDAILY_PASSWORD}").unwrap();
+        assert_eq!(
+            password,
+            Password::Ref(PasswordRef::Env {
+                name: "CPDAILY_PASSWORD".to_string()
+            })
+        );
+        assert_eq!(
+            format!("{:?}", Password::Plain("hunter2".to_string())),
+            "Plain(***)"
+        );
+    }
+
+    #[test]
+    fn test_password_resolve() {
+        let path = env::temp_dir().join(format!("password-{}", uuid::Uuid::new_v4()));
+        std::fs::write(&path, "hunter2\n").unwrap();
+        let password = Password::Ref(PasswordRef::File {
+            path: path.to_str().unwrap().to_string(),
+        });
+        assert_eq!(password.resolve().unwrap(), "hunter2");
+        std::fs::remove_file(&path).unwrap();
+        assert!(password.resolve().is_err());
+
+        let password = Password::Ref(PasswordRef::Command {
+            command: "echo hunter2".to_string(),
+        });
+        assert_eq!(password.resolve().unwrap(), "hunter2");
+
+        let password = Password::Ref(PasswordRef::Env {
+            name: "IAMALIVE_TEST_PASSWORD_UNSET".to_string(),
+        });
+        assert!(password.resolve().is_err());
+    }
+
+    #[test]
+    fn test_load_config_dir() {
+        let dir = env::temp_dir().join(format!("conf.d-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        fs::write(
+            dir.join("_defaults.yml"),
+            r#"
+session_dir: sessions
+defaults:
+  school: "10000"
+  password: hunter2
+  address: 默认地址
+  device_info:
+    model: Galaxy Nexus
+    app_version: "9.0.12"
+    system_version: "11.0.0"
+    system_name: android
+    device_id: "0123456789abcdef"
+    lat: 36.123456
+    lon: 120.789012
+    user_agent: okhttp/3.12.4
+  actions: []
+"#,
+        )
+        .unwrap();
+        fs::write(dir.join("alice.yml"), "username: alice\n").unwrap();
+        fs::write(
+            dir.join("others.yml"),
+            "- username: bob\n- username: carol\n  school: \"10001\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("notes.txt"), "not a config").unwrap();
+
+        let config = load_config(dir.to_str().unwrap()).unwrap();
+        assert_eq!(config.session_dir, Some("sessions".to_string()));
+        let users: Vec<(&str, &str)> = config
+            .users
+            .iter()
+            .map(|u| (u.school.as_str(), u.username.as_str()))
+            .collect();
+        assert_eq!(
+            users,
+            vec![("10000", "alice"), ("10000", "bob"), ("10001", "carol")]
+        );
+        assert_eq!(config.users[0].password.as_str().unwrap(), "hunter2");
+
+        fs::write(dir.join("zoe.yml"), "username: bob\n").unwrap();
+        let err = load_config(dir.to_str().unwrap()).unwrap_err();
+        assert!(err.to_string().starts_with("Duplicate user 10000:bob in "));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_load_config_missing_password() {
+        let path = env::temp_dir().join(format!("config-{}.yml", uuid::Uuid::new_v4()));
+        let config = |extra: &str| {
+            format!(
+                r#"
+defaults:
+  school: "10000"
+  address: 默认地址
+  device_profile: mi-6
+  device_info:
+    lat: 36.1
+    lon: 120.7
+  actions: []
+users:
+  - username: alice
+{}"#,
+                extra
+            )
+        };
+
+        fs::write(&path, config("")).unwrap();
+        let err = load_config(path.to_str().unwrap()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "10000:alice has no password, set one or use login_mode Sms"
+        );
+
+        fs::write(
+            &path,
+            config("    login_mode:\n      type: Sms\n      mobile: \"13800000000\"\n"),
+        )
+        .unwrap();
+        assert!(load_config(path.to_str().unwrap()).is_ok());
+
+        fs::write(&path, config("    mod_auth_cas: ST-1234\n")).unwrap();
+        assert!(load_config(path.to_str().unwrap()).is_ok());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_password_encryption_per_school() {
+        let path = env::temp_dir().join(format!("config-{}.yml", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"
+password_encryption:
+  "10000": Never
+defaults:
+  school: "10000"
+  password: hunter2
+  address: somewhere
+  device_profile: mi-6
+  device_info:
+    lat: 36.1
+    lon: 120.7
+  actions: []
+users:
+  - username: alice
+  - username: bob
+    password_encryption: Always
+  - username: carol
+    school: "10001"
+"#,
+        )
+        .unwrap();
+        let config = load_config(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let encryption: Vec<PasswordEncryption> = config
+            .users
+            .iter()
+            .map(|u| u.get_login_options().password_encryption)
+            .collect();
+        assert_eq!(
+            encryption,
+            vec![
+                PasswordEncryption::Never,
+                PasswordEncryption::Always,
+                PasswordEncryption::Auto
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_cron() {
+        use chrono::{Datelike, TimeZone, Utc, Weekday};
+
+        let weekdays = |cron: &str| -> Vec<Weekday> {
+            let schedule = Schedule {
+                cron: cron.to_string(),
+                jitter: 0,
+                form_windows: false,
+            };
+            // a Sunday
+            let start = Utc.ymd(2021, 11, 7).and_hms(0, 0, 0);
+            let mut days: Vec<Weekday> = schedule
+                .parse_cron()
+                .unwrap()
+                .after(&start)
+                .take_while(|time| *time < start + chrono::Duration::days(7))
+                .map(|time| time.weekday())
+                .collect();
+            days.dedup();
+            days
+        };
+        use Weekday::*;
+        assert_eq!(weekdays("0 8 * * 1-5"), vec![Mon, Tue, Wed, Thu, Fri]);
+        assert_eq!(weekdays("0 8 * * 0"), vec![Sun]);
+        assert_eq!(weekdays("0 8 * * 7"), vec![Sun]);
+        assert_eq!(weekdays("0 8 * * 5-7"), vec![Sun, Fri, Sat]);
+        assert_eq!(weekdays("0 8 * * mon,wed"), vec![Mon, Wed]);
+        assert_eq!(weekdays("0 8 * * */2"), vec![Sun, Tue, Thu, Sat]);
+        assert_eq!(weekdays("0 8 * * *").len(), 7);
+        // with a seconds field the expression goes to the cron crate as is
+        assert_eq!(weekdays("0 0 8 * * 2"), vec![Mon]);
+
+        for invalid in ["0 8 * * 8", "0 8 * * 5-1", "0 8 * * someday", "0 8 * * */0"] {
+            let schedule = Schedule {
+                cron: invalid.to_string(),
+                jitter: 0,
+                form_windows: false,
+            };
+            assert!(schedule.parse_cron().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_with_app_version() {
+        let config = parse_config(
+            br#"
+app_version:
+  type: File
+  path: app_version.txt
+users:
+  - school: "10000"
+    username: alice
+    address: somewhere
+    device_profile: mi-6
+    device_info:
+      lat: 36.1
+      lon: 120.7
+    actions: []
+  - school: "10000"
+    username: bob
+    address: somewhere
+    app_version_pin: "9.0.12"
+    device_profile: mi-6
+    device_info:
+      lat: 36.1
+      lon: 120.7
+    actions: []
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.app_version,
+            AppVersionSource::File {
+                path: "app_version.txt".to_string()
+            }
+        );
+
+        let alice = config.users[0].with_app_version(Some("9.2.1"));
+        assert_eq!(alice.device_info.app_version, "9.2.1");
+        assert!(alice
+            .device_info
+            .user_agent
+            .ends_with("cpdaily/9.2.1 wisedu/9.2.1"));
+        let bob = config.users[1].with_app_version(Some("9.2.1"));
+        assert_eq!(bob.device_info.app_version, "9.0.12");
+        assert!(bob
+            .device_info
+            .user_agent
+            .ends_with("cpdaily/9.0.12 wisedu/9.0.12"));
+        assert_eq!(config.users[0].with_app_version(None), config.users[0]);
+    }
 }
@@ -0,0 +1,301 @@
+use anyhow::{anyhow, bail};
+use serde_yaml::{Mapping, Value};
+
+use crate::cpdaily::crypto::ciphers::md5;
+
+pub const APP_VERSION: &str = "9.0.12";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum System {
+    Android,
+    Ios,
+}
+
+// a device as it really shipped, so model, system and user agent agree
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DeviceProfile {
+    pub name: &'static str,
+    pub model: &'static str,
+    pub system: System,
+    pub system_version: &'static str,
+    // Build/... for android, the Mobile/... tag for iOS
+    pub build: &'static str,
+    pub chrome_version: &'static str,
+}
+
+pub const PROFILES: [DeviceProfile; 10] = [
+    DeviceProfile {
+        name: "mi-6",
+        model: "MI 6",
+        system: System::Android,
+        system_version: "8.0.0",
+        build: "OPR1.170623.027",
+        chrome_version: "92.0.4515.131",
+    },
+    DeviceProfile {
+        name: "mi-10",
+        model: "Mi 10",
+        system: System::Android,
+        system_version: "11",
+        build: "RKQ1.200826.002",
+        chrome_version: "92.0.4515.131",
+    },
+    DeviceProfile {
+        name: "redmi-k40",
+        model: "M2012K11AC",
+        system: System::Android,
+        system_version: "11",
+        build: "RKQ1.200826.002",
+        chrome_version: "91.0.4472.120",
+    },
+    DeviceProfile {
+        name: "huawei-p30",
+        model: "ELE-AL00",
+        system: System::Android,
+        system_version: "10",
+        build: "HUAWEIELE-AL00",
+        chrome_version: "88.0.4324.93",
+    },
+    DeviceProfile {
+        name: "oppo-reno5",
+        model: "PEGM00",
+        system: System::Android,
+        system_version: "11",
+        build: "RP1A.200720.011",
+        chrome_version: "90.0.4430.210",
+    },
+    DeviceProfile {
+        name: "vivo-x60",
+        model: "V2046A",
+        system: System::Android,
+        system_version: "11",
+        build: "RP1A.200720.012",
+        chrome_version: "87.0.4280.141",
+    },
+    DeviceProfile {
+        name: "galaxy-s10",
+        model: "SM-G9730",
+        system: System::Android,
+        system_version: "11",
+        build: "RP1A.200720.012",
+        chrome_version: "92.0.4515.115",
+    },
+    DeviceProfile {
+        name: "iphone-xr",
+        model: "iPhone11,8",
+        system: System::Ios,
+        system_version: "14.4",
+        build: "15E148",
+        chrome_version: "",
+    },
+    DeviceProfile {
+        name: "iphone-12",
+        model: "iPhone13,2",
+        system: System::Ios,
+        system_version: "14.6",
+        build: "15E148",
+        chrome_version: "",
+    },
+    DeviceProfile {
+        name: "iphone-13",
+        model: "iPhone14,5",
+        system: System::Ios,
+        system_version: "15.0",
+        build: "15E148",
+        chrome_version: "",
+    },
+];
+
+impl DeviceProfile {
+    pub fn find(name: &str) -> anyhow::Result<&'static DeviceProfile> {
+        PROFILES
+            .iter()
+            .find(|profile| profile.name == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "Unknown device profile \"{}\", expected auto or one of {}",
+                    name,
+                    PROFILES
+                        .iter()
+                        .map(|profile| profile.name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })
+    }
+
+    // the same user always lands on the same device
+    pub fn auto(school: &str, username: &str) -> &'static DeviceProfile {
+        let seed = user_hash(school, username);
+        let index = u64::from_str_radix(&seed[..8], 16).unwrap() as usize % PROFILES.len();
+        &PROFILES[index]
+    }
+
+    pub fn system_name(&self) -> &'static str {
+        match self.system {
+            System::Android => "android",
+            System::Ios => "iOS",
+        }
+    }
+
+    pub fn user_agent(&self, app_version: &str) -> String {
+        match self.system {
+            System::Android => format!(
+                "Mozilla/5.0 (Linux; Android {}; {} Build/{}; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/{} Mobile Safari/537.36 okhttp/3.12.4 cpdaily/{} wisedu/{}",
+                self.system_version, self.model, self.build, self.chrome_version, app_version, app_version
+            ),
+            System::Ios => format!(
+                "Mozilla/5.0 (iPhone; CPU iPhone OS {} like Mac OS X) AppleWebKit/605.1.15 (KHTML, like Gecko) Mobile/{} cpdaily/{} wisedu/{}",
+                self.system_version.replace('.', "_"), self.build, app_version, app_version
+            ),
+        }
+    }
+
+    // android ids are 16 hex digits, iOS ones uppercase uuids
+    pub fn device_id(&self, school: &str, username: &str) -> String {
+        let seed = user_hash(school, username);
+        match self.system {
+            System::Android => seed[16..].to_string(),
+            System::Ios => format!(
+                "{}-{}-{}-{}-{}",
+                &seed[..8],
+                &seed[8..12],
+                &seed[12..16],
+                &seed[16..20],
+                &seed[20..]
+            )
+            .to_uppercase(),
+        }
+    }
+}
+
+fn user_hash(school: &str, username: &str) -> String {
+    md5::hash(&format!("{}:{}", school, username)).unwrap()
+}
+
+fn scalar_to_string(value: Option<&Value>) -> String {
+    match value {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Number(n)) => n.to_string(),
+        _ => "".to_string(),
+    }
+}
+
+// fill in the device_info of a user with `device_profile` set; the profile
+// wins over device_info inherited from defaults and templates, while the
+// fields written out in the user's own device_info win over the profile
+pub fn apply(user: &mut Value, own: Option<&Mapping>) -> anyhow::Result<()> {
+    let profile = match user.get("device_profile") {
+        None | Some(Value::Null) => return Ok(()),
+        Some(Value::String(name)) if name == "auto" => DeviceProfile::auto(
+            &scalar_to_string(user.get("school")),
+            &scalar_to_string(user.get("username")),
+        ),
+        Some(Value::String(name)) => DeviceProfile::find(name)?,
+        Some(_) => bail!("device_profile must be auto or the name of a profile"),
+    };
+    let school = scalar_to_string(user.get("school"));
+    let username = scalar_to_string(user.get("username"));
+    let own = own.cloned().unwrap_or_default();
+
+    let mut device_info = match user.get("device_info") {
+        Some(Value::Mapping(device_info)) => device_info.clone(),
+        _ => Mapping::new(),
+    };
+    // not a property of the device, an inherited one is kept
+    let app_version = device_info
+        .get(&Value::from("app_version"))
+        .and_then(|v| v.as_str())
+        .unwrap_or(APP_VERSION)
+        .to_owned();
+    device_info.insert(Value::from("app_version"), Value::from(app_version.clone()));
+    let generated = [
+        ("model", profile.model.to_string()),
+        ("system_version", profile.system_version.to_string()),
+        ("system_name", profile.system_name().to_string()),
+        ("device_id", profile.device_id(&school, &username)),
+        ("user_agent", profile.user_agent(&app_version)),
+    ];
+    for (field, value) in generated {
+        let field = Value::from(field);
+        if !own.contains_key(&field) {
+            device_info.insert(field, Value::from(value));
+        }
+    }
+
+    if let Value::Mapping(user) = user {
+        user.insert(Value::from("device_info"), Value::Mapping(device_info));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::{apply, DeviceProfile, PROFILES};
+
+    #[test]
+    fn test_profiles() {
+        let mi6 = DeviceProfile::find("mi-6").unwrap();
+        assert_eq!(
+            mi6.user_agent("9.0.12"),
+            "Mozilla/5.0 (Linux; Android 8.0.0; MI 6 Build/OPR1.170623.027; wv) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/92.0.4515.131 Mobile Safari/537.36 okhttp/3.12.4 cpdaily/9.0.12 wisedu/9.0.12"
+        );
+        assert_eq!(mi6.device_id("10000", "alice").len(), 16);
+
+        let iphone = DeviceProfile::find("iphone-12").unwrap();
+        assert!(iphone
+            .user_agent("9.0.12")
+            .starts_with("Mozilla/5.0 (iPhone; CPU iPhone OS 14_6 like Mac OS X)"));
+        assert_eq!(iphone.device_id("10000", "alice").len(), 36);
+
+        assert!(DeviceProfile::find("nokia-3310").is_err());
+        for profile in PROFILES.iter() {
+            assert_eq!(DeviceProfile::find(profile.name).unwrap(), profile);
+        }
+    }
+
+    #[test]
+    fn test_auto_is_stable() {
+        assert_eq!(
+            DeviceProfile::auto("10000", "alice"),
+            DeviceProfile::auto("10000", "alice")
+        );
+        assert_eq!(
+            DeviceProfile::find("mi-6")
+                .unwrap()
+                .device_id("10000", "alice"),
+            DeviceProfile::find("mi-10")
+                .unwrap()
+                .device_id("10000", "alice")
+        );
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut user: Value = serde_yaml::from_str(
+            "school: \"10000\"\nusername: 2019001\ndevice_profile: mi-6\ndevice_info:\n  app_version: \"9.1.0\"\n  lat: 36.1\n  lon: 120.7\n",
+        )
+        .unwrap();
+        let own = user["device_info"].as_mapping().cloned();
+        apply(&mut user, own.as_ref()).unwrap();
+        let device_info = &user["device_info"];
+        assert_eq!(device_info["model"], Value::from("MI 6"));
+        assert_eq!(device_info["system_name"], Value::from("android"));
+        assert_eq!(device_info["app_version"], Value::from("9.1.0"));
+        assert!(device_info["user_agent"]
+            .as_str()
+            .unwrap()
+            .ends_with("cpdaily/9.1.0 wisedu/9.1.0"));
+        assert_eq!(
+            device_info["device_id"],
+            Value::from(
+                DeviceProfile::find("mi-6")
+                    .unwrap()
+                    .device_id("10000", "2019001")
+            )
+        );
+        assert_eq!(device_info["lat"], Value::from(36.1));
+    }
+}
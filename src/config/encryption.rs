@@ -0,0 +1,282 @@
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::Path,
+    process::Command,
+};
+
+use anyhow::{anyhow, bail};
+use openssl::base64::{decode_block, encode_block};
+use openssl::hash::MessageDigest;
+use openssl::pkcs5::pbkdf2_hmac;
+use openssl::rand::rand_bytes;
+use openssl::symm::{decrypt as cipher_decrypt, encrypt as cipher_encrypt, Cipher};
+
+use super::check_config;
+use crate::cpdaily::session;
+
+// same layout as `openssl enc -aes-256-cbc -pbkdf2 -a`, so the file can
+// still be opened by hand when this program is not around
+const MAGIC: &[u8] = b"Salted__";
+const ITERATIONS: usize = 10000;
+
+pub const PASSPHRASE_ENV: &str = "CONFIG_PASSPHRASE";
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let cipher = Cipher::aes_256_cbc();
+    let key_len = cipher.key_len();
+    let mut key_iv = vec![0; key_len + cipher.iv_len().unwrap()];
+    pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key_iv,
+    )?;
+    let iv = key_iv.split_off(key_len);
+    Ok((key_iv, iv))
+}
+
+fn decode(contents: &[u8]) -> Option<Vec<u8>> {
+    if contents.starts_with(MAGIC) {
+        return Some(contents.to_vec());
+    }
+    let text: String = std::str::from_utf8(contents)
+        .ok()?
+        .chars()
+        .filter(|c| !c.is_whitespace())
+        .collect();
+    decode_block(&text)
+        .ok()
+        .filter(|raw| raw.starts_with(MAGIC))
+}
+
+pub fn is_encrypted(contents: &[u8]) -> bool {
+    decode(contents).is_some()
+}
+
+pub fn encrypt(plaintext: &[u8], passphrase: &str) -> anyhow::Result<String> {
+    let mut salt = [0; 8];
+    rand_bytes(&mut salt)?;
+    let (key, iv) = derive_key(passphrase, &salt)?;
+    let mut raw = MAGIC.to_vec();
+    raw.extend_from_slice(&salt);
+    raw.extend(cipher_encrypt(
+        Cipher::aes_256_cbc(),
+        &key,
+        Some(&iv),
+        plaintext,
+    )?);
+
+    let encoded = encode_block(&raw);
+    let lines: Vec<&str> = encoded
+        .as_bytes()
+        .chunks(64)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect();
+    Ok(format!("{}\n", lines.join("\n")))
+}
+
+pub fn decrypt(contents: &[u8], passphrase: &str) -> anyhow::Result<Vec<u8>> {
+    let raw = decode(contents).ok_or_else(|| anyhow!("Config is not encrypted"))?;
+    if raw.len() < MAGIC.len() + 8 {
+        bail!("Encrypted config is truncated");
+    }
+    let (salt, ciphertext) = raw[MAGIC.len()..].split_at(8);
+    let (key, iv) = derive_key(passphrase, salt)?;
+    // a wrong key still ends in valid padding once in 256 tries, but the
+    // garbage is no YAML
+    cipher_decrypt(Cipher::aes_256_cbc(), &key, Some(&iv), ciphertext)
+        .ok()
+        .filter(|plaintext| std::str::from_utf8(plaintext).is_ok())
+        .ok_or_else(|| anyhow!("Wrong passphrase or corrupted config"))
+}
+
+pub fn read_line_hidden() -> io::Result<String> {
+    #[cfg(unix)]
+    let saved = unsafe {
+        let mut termios: libc::termios = std::mem::zeroed();
+        if libc::tcgetattr(libc::STDIN_FILENO, &mut termios) == 0 {
+            let saved = termios;
+            termios.c_lflag &= !libc::ECHO;
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios);
+            Some(saved)
+        } else {
+            None
+        }
+    };
+
+    let mut line = String::new();
+    let result = io::stdin().read_line(&mut line);
+
+    #[cfg(unix)]
+    if let Some(saved) = saved {
+        unsafe {
+            libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &saved);
+        }
+        eprintln!();
+    }
+
+    result?;
+    Ok(line.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+// taken from CONFIG_PASSPHRASE, or asked on the terminal
+pub fn get_passphrase(confirm: bool) -> anyhow::Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    eprint!("Config passphrase: ");
+    io::stderr().flush()?;
+    let passphrase = read_line_hidden()?;
+    if passphrase.is_empty() {
+        bail!("Empty passphrase");
+    }
+    if confirm {
+        eprint!("Repeat passphrase: ");
+        io::stderr().flush()?;
+        if read_line_hidden()? != passphrase {
+            bail!("Passphrases do not match");
+        }
+    }
+    Ok(passphrase)
+}
+
+// the config is the only copy of everyone's credentials, a crash halfway
+// must not cost it; `mode` defaults to the one the file has
+fn write_config(path: &str, contents: &[u8], mode: Option<u32>) -> anyhow::Result<()> {
+    let path = Path::new(path);
+    let mode = mode.unwrap_or_else(|| session::file_mode(path, 0o600));
+    session::write_atomic(path, contents, mode)
+}
+
+pub fn encrypt_file(path: &str) -> anyhow::Result<()> {
+    let contents = fs::read(path)?;
+    if is_encrypted(&contents) {
+        bail!("{} is already encrypted", path);
+    }
+    check_config(&contents)?;
+    let passphrase = get_passphrase(true)?;
+    write_config(path, encrypt(&contents, &passphrase)?.as_bytes(), None)
+}
+
+pub fn decrypt_file(path: &str) -> anyhow::Result<()> {
+    let contents = fs::read(path)?;
+    if !is_encrypted(&contents) {
+        bail!("{} is not encrypted", path);
+    }
+    let plaintext = decrypt(&contents, &get_passphrase(false)?)?;
+    // the passwords are readable now, keep them from other local users
+    write_config(path, &plaintext, Some(0o600))
+}
+
+fn run_editor(path: &std::path::Path) -> anyhow::Result<()> {
+    let editor = env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| {
+            if cfg!(windows) {
+                "notepad".to_string()
+            } else {
+                "vi".to_string()
+            }
+        });
+    let mut args = editor.split_whitespace();
+    let program = args.next().ok_or_else(|| anyhow!("Empty $EDITOR"))?;
+    let status = Command::new(program).args(args).arg(path).status()?;
+    if !status.success() {
+        bail!("\"{}\" exited with {}", editor, status);
+    }
+    Ok(())
+}
+
+// decrypt into a private temporary file, open it in $EDITOR and encrypt
+// the result back with the same passphrase
+pub fn edit_file(path: &str) -> anyhow::Result<()> {
+    let contents = fs::read(path)?;
+    if !is_encrypted(&contents) {
+        bail!("{} is not encrypted", path);
+    }
+    let passphrase = get_passphrase(false)?;
+    let plaintext = decrypt(&contents, &passphrase)?;
+
+    let mut file = tempfile::Builder::new()
+        .prefix("config-")
+        .suffix(".yml")
+        .tempfile()?;
+    file.write_all(&plaintext)?;
+    file.flush()?;
+
+    loop {
+        run_editor(file.path())?;
+        let edited = fs::read(file.path())?;
+        if edited == plaintext {
+            eprintln!("No changes");
+            return Ok(());
+        }
+        match check_config(&edited) {
+            Ok(_) => {
+                return write_config(path, encrypt(&edited, &passphrase)?.as_bytes(), None);
+            }
+            Err(err) => {
+                eprint!("{:#}\nEdit again? [Y/n] ", err);
+                io::stderr().flush()?;
+                let mut answer = String::new();
+                io::stdin().read_line(&mut answer)?;
+                if answer.trim().eq_ignore_ascii_case("n") {
+                    bail!("{} left unchanged", path);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decrypt, decrypt_file, encrypt, encrypt_file, is_encrypted, PASSPHRASE_ENV};
+
+    #[test]
+    fn test_encrypt_roundtrip() {
+        let plaintext = b"users: []\n";
+        let encrypted = encrypt(plaintext, "hunter2").unwrap();
+        assert!(is_encrypted(encrypted.as_bytes()));
+        assert!(!is_encrypted(plaintext));
+        assert_eq!(
+            decrypt(encrypted.as_bytes(), "hunter2").unwrap(),
+            plaintext.to_vec()
+        );
+        assert!(decrypt(encrypted.as_bytes(), "hunter3").is_err());
+    }
+
+    #[test]
+    fn test_decrypt_openssl_output() {
+        // printf 'users: []\n' | openssl enc -aes-256-cbc -pbkdf2 -a -pass pass:hunter2
+        let encrypted = b"U2FsdGVkX1/yPsjUDR3MedAulOES3f7vU98C8/PorOA=\n";
+        assert_eq!(
+            decrypt(encrypted, "hunter2").unwrap(),
+            b"users: []\n".to_vec()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_encrypt_file_mode() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("config-{}.yml", uuid::Uuid::new_v4()));
+        let mode = || fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+        fs::write(&path, "users: []\n").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        std::env::set_var(PASSPHRASE_ENV, "hunter2");
+
+        encrypt_file(path.to_str().unwrap()).unwrap();
+        assert!(is_encrypted(&fs::read(&path).unwrap()));
+        assert_eq!(mode(), 0o640);
+
+        decrypt_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "users: []\n");
+        assert_eq!(mode(), 0o600);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use anyhow::{anyhow, bail, Context};
+use serde_yaml::{Mapping, Value};
+
+use super::device_profile;
+
+// lists merged item by item instead of being replaced, matched on the
+// given field of each item
+const KEYED_LISTS: [(&str, &str); 2] = [("actions", "type"), ("form_data", "question")];
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_owned())
+}
+
+fn merge_list(base: &mut Vec<Value>, over: Vec<Value>, id: &str) {
+    for item in over {
+        let existing = item
+            .get(id)
+            .and_then(|id_value| base.iter_mut().find(|b| b.get(id) == Some(id_value)));
+        match existing {
+            Some(existing) => merge(existing, item),
+            None => base.push(item),
+        }
+    }
+}
+
+fn merge_mapping(base: &mut Mapping, over: Mapping) {
+    for (k, v) in over {
+        let id = KEYED_LISTS
+            .iter()
+            .find(|(field, _)| k.as_str() == Some(*field))
+            .map(|(_, id)| *id);
+        match (base.get_mut(&k), v) {
+            (Some(Value::Sequence(b)), Value::Sequence(v)) if id.is_some() => {
+                merge_list(b, v, id.unwrap())
+            }
+            (Some(b), v) => merge(b, v),
+            (None, v) => {
+                base.insert(k, v);
+            }
+        }
+    }
+}
+
+// deep merge `over` into `base`, `over` wins on conflicts
+pub fn merge(base: &mut Value, over: Value) {
+    match (base, over) {
+        (Value::Mapping(base), Value::Mapping(over)) => merge_mapping(base, over),
+        (base, over) => *base = over,
+    }
+}
+
+fn resolve_template(
+    templates: &Mapping,
+    name: &str,
+    seen: &mut Vec<String>,
+) -> anyhow::Result<Value> {
+    if seen.iter().any(|s| s == name) {
+        bail!(
+            "Template \"{}\" extends itself through {}",
+            name,
+            seen.join(" -> ")
+        );
+    }
+    let template = templates
+        .get(&key(name))
+        .cloned()
+        .ok_or_else(|| anyhow!("Unknown template \"{}\"", name))?;
+    seen.push(name.to_owned());
+    apply_extends(templates, template, seen)
+}
+
+// merge `value` over the template it `extends`, if any
+fn apply_extends(
+    templates: &Mapping,
+    mut value: Value,
+    seen: &mut Vec<String>,
+) -> anyhow::Result<Value> {
+    let extends = match &mut value {
+        Value::Mapping(m) => m.remove(&key("extends")),
+        _ => None,
+    };
+    match extends {
+        None => Ok(value),
+        Some(Value::String(name)) => {
+            let mut base = resolve_template(templates, &name, seen)?;
+            merge(&mut base, value);
+            Ok(base)
+        }
+        Some(_) => bail!("extends must be the name of a template"),
+    }
+}
+
+// turn every entry of `users` into a complete user: defaults, then the
+// template chain it extends, then the entry itself; its device profile goes
+// over what was inherited but not over the entry's own device_info
+pub fn expand(config: &mut Value) -> anyhow::Result<()> {
+    let root = match config {
+        Value::Mapping(root) => root,
+        // not a config at all, leave it to serde to complain
+        _ => return Ok(()),
+    };
+    let defaults = root.get(&key("defaults")).cloned().unwrap_or(Value::Null);
+    let templates = match root.get(&key("templates")) {
+        Some(Value::Mapping(templates)) => templates.clone(),
+        Some(Value::Null) | None => Mapping::new(),
+        Some(_) => bail!("templates must be a mapping of names to templates"),
+    };
+
+    if let Some(Value::Sequence(users)) = root.get_mut(&key("users")) {
+        for (i, user) in users.iter_mut().enumerate() {
+            let own = user.get("device_info").and_then(Value::as_mapping).cloned();
+            let expanded = apply_extends(&templates, user.clone(), &mut vec![])
+                .with_context(|| format!("Failed to expand user #{}", i + 1))?;
+            let mut merged = match &defaults {
+                Value::Null => Value::Mapping(Mapping::new()),
+                defaults => defaults.clone(),
+            };
+            merge(&mut merged, expanded);
+            device_profile::apply(&mut merged, own.as_ref())
+                .with_context(|| format!("Failed to expand user #{}", i + 1))?;
+            *user = merged;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::Value;
+
+    use super::expand;
+    use crate::config::device_profile::DeviceProfile;
+
+    fn expand_str(yaml: &str) -> anyhow::Result<Value> {
+        let mut value: Value = serde_yaml::from_str(yaml).unwrap();
+        expand(&mut value)?;
+        Ok(value["users"].clone())
+    }
+
+    #[test]
+    fn test_expand() {
+        let users = expand_str(
+            r#"
+defaults:
+  address: 默认地址
+  device_info:
+    model: Galaxy Nexus
+    app_version: "9.0.12"
+templates:
+  dorm:
+    actions:
+      - type: CounselorFormFill
+        force_submit: false
+        form_data:
+          - question: 所在地
+            answer: 宿舍
+          - question: 体温
+            answer: "36.5"
+  sick:
+    extends: dorm
+    actions:
+      - type: CounselorFormFill
+        form_data:
+          - question: 体温
+            answer: "37.2"
+users:
+  - school: a
+    extends: sick
+    device_info:
+      model: MI 6
+    actions:
+      - type: CounselorFormFill
+        force_submit: true
+        form_data:
+          - question: 身体状况
+            answer: 健康
+  - school: b
+    address: 另一个地址
+"#,
+        )
+        .unwrap();
+
+        let expected: Value = serde_yaml::from_str(
+            r#"
+- address: 默认地址
+  device_info:
+    model: MI 6
+    app_version: "9.0.12"
+  actions:
+    - type: CounselorFormFill
+      force_submit: true
+      form_data:
+        - question: 所在地
+          answer: 宿舍
+        - question: 体温
+          answer: "37.2"
+        - question: 身体状况
+          answer: 健康
+  school: a
+- address: 另一个地址
+  device_info:
+    model: Galaxy Nexus
+    app_version: "9.0.12"
+  school: b
+"#,
+        )
+        .unwrap();
+        assert_eq!(users, expected);
+    }
+
+    #[test]
+    fn test_expand_device_profile() {
+        let users = expand_str(
+            r#"
+defaults:
+  device_profile: mi-6
+  device_info:
+    model: Galaxy Nexus
+    app_version: "9.1.0"
+    system_name: android
+    device_id: "0123456789abcdef"
+    user_agent: okhttp/3.12.4
+    lat: 36.1
+    lon: 120.7
+users:
+  - school: "10000"
+    username: alice
+  - school: "10000"
+    username: bob
+    device_profile: iphone-12
+    device_info:
+      device_id: "fedcba9876543210"
+"#,
+        )
+        .unwrap();
+
+        let mi6 = DeviceProfile::find("mi-6").unwrap();
+        let alice = &users[0]["device_info"];
+        assert_eq!(alice["model"], Value::from("MI 6"));
+        assert_eq!(
+            alice["device_id"],
+            Value::from(mi6.device_id("10000", "alice"))
+        );
+        assert_eq!(alice["user_agent"], Value::from(mi6.user_agent("9.1.0")));
+        assert_eq!(alice["lat"], Value::from(36.1));
+
+        let iphone = DeviceProfile::find("iphone-12").unwrap();
+        let bob = &users[1]["device_info"];
+        assert_eq!(bob["model"], Value::from("iPhone13,2"));
+        assert_eq!(bob["system_name"], Value::from("iOS"));
+        assert_eq!(bob["user_agent"], Value::from(iphone.user_agent("9.1.0")));
+        // written out on the user itself, so it stays
+        assert_eq!(bob["device_id"], Value::from("fedcba9876543210"));
+    }
+
+    #[test]
+    fn test_expand_errors() {
+        let err = expand_str("users:\n  - extends: missing\n").unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to expand user #1: Unknown template \"missing\""
+        );
+
+        let err = expand_str(
+            "templates:\n  a:\n    extends: b\n  b:\n    extends: a\nusers:\n  - extends: a\n",
+        )
+        .unwrap_err();
+        assert_eq!(
+            format!("{:#}", err),
+            "Failed to expand user #1: Template \"a\" extends itself through a -> b"
+        );
+    }
+}
@@ -0,0 +1,773 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+use serde_yaml::{Mapping, Value};
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::Marker;
+
+use super::{
+    is_defaults_file, list_config_dir, read_config_file, templates, Action, Config, DeviceInfo,
+    Schedule, User,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+fn display_path(path: &[Segment]) -> String {
+    let mut out = String::new();
+    for segment in path {
+        match segment {
+            Segment::Key(key) if out.is_empty() => out.push_str(key),
+            Segment::Key(key) => {
+                out.push('.');
+                out.push_str(key);
+            }
+            Segment::Index(i) => out.push_str(&format!("[{}]", i)),
+        }
+    }
+    out
+}
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_owned())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub level: Level,
+    pub file: PathBuf,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = match self.level {
+            Level::Error => "error",
+            Level::Warning => "warning",
+        };
+        match self.line {
+            Some(line) => write!(
+                f,
+                "{}:{}: {}: {}",
+                self.file.display(),
+                line,
+                level,
+                self.message
+            ),
+            None => write!(f, "{}: {}: {}", self.file.display(), level, self.message),
+        }
+    }
+}
+
+enum Frame {
+    // the key whose value is being read, if any
+    Map(Option<String>),
+    Seq(usize),
+}
+
+// the line of every key and sequence item in a yaml document, as serde_yaml
+// forgets them once the document is turned into a Value
+#[derive(Default)]
+struct Spans {
+    lines: HashMap<Vec<Segment>, usize>,
+    stack: Vec<Frame>,
+    path: Vec<Segment>,
+}
+
+impl Spans {
+    fn parse(text: &str) -> Self {
+        let mut spans = Spans::default();
+        // syntax errors have been reported by serde_yaml already
+        let _ = Parser::new(text.chars()).load(&mut spans, false);
+        spans
+    }
+
+    fn get(&self, path: &[Segment]) -> Option<usize> {
+        self.lines.get(path).copied()
+    }
+
+    fn node(&mut self, mark: Marker, scalar: Option<String>, container: bool) {
+        match self.stack.last_mut() {
+            None => {
+                self.lines.insert(vec![], mark.line());
+            }
+            Some(Frame::Map(current @ None)) => {
+                // a key, its value comes next
+                let key = scalar.unwrap_or_default();
+                let mut path = self.path.clone();
+                path.push(Segment::Key(key.clone()));
+                self.lines.insert(path, mark.line());
+                *current = Some(key);
+                return;
+            }
+            Some(Frame::Map(Some(key))) => {
+                let key = key.clone();
+                self.path.push(Segment::Key(key));
+            }
+            Some(Frame::Seq(i)) => {
+                let i = *i;
+                self.path.push(Segment::Index(i));
+                self.lines.insert(self.path.clone(), mark.line());
+            }
+        }
+        if !container {
+            self.end_value();
+        }
+    }
+
+    fn end_value(&mut self) {
+        match self.stack.last_mut() {
+            Some(Frame::Map(current)) => {
+                *current = None;
+                self.path.pop();
+            }
+            Some(Frame::Seq(i)) => {
+                *i += 1;
+                self.path.pop();
+            }
+            None => {}
+        }
+    }
+}
+
+impl MarkedEventReceiver for Spans {
+    fn on_event(&mut self, ev: Event, mark: Marker) {
+        match ev {
+            Event::Scalar(value, ..) => self.node(mark, Some(value), false),
+            Event::Alias(_) => self.node(mark, None, false),
+            Event::SequenceStart(_) => {
+                self.node(mark, None, true);
+                self.stack.push(Frame::Seq(0));
+            }
+            Event::MappingStart(_) => {
+                self.node(mark, None, true);
+                self.stack.push(Frame::Map(None));
+            }
+            Event::SequenceEnd | Event::MappingEnd => {
+                self.stack.pop();
+                self.end_value();
+            }
+            _ => {}
+        }
+    }
+}
+
+// keys of `input` that did not survive a round trip through the schema,
+// i.e. keys serde silently ignored
+fn find_unknown_keys(
+    input: &Value,
+    known: &Value,
+    path: &mut Vec<Segment>,
+    found: &mut Vec<Vec<Segment>>,
+) {
+    match (input, known) {
+        (Value::Mapping(input), Value::Mapping(known)) => {
+            for (k, v) in input {
+                let name = k
+                    .as_str()
+                    .map(|k| k.to_owned())
+                    .unwrap_or_else(|| format!("{:?}", k));
+                path.push(Segment::Key(name));
+                match known.get(k) {
+                    Some(known) => find_unknown_keys(v, known, path, found),
+                    None => found.push(path.clone()),
+                }
+                path.pop();
+            }
+        }
+        (Value::Sequence(input), Value::Sequence(known)) => {
+            for (i, (v, known)) in input.iter().zip(known).enumerate() {
+                path.push(Segment::Index(i));
+                find_unknown_keys(v, known, path, found);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_device_info(device_info: &DeviceInfo) -> Vec<(&'static str, String)> {
+    let mut problems = vec![];
+    let device_id = &device_info.device_id;
+    let is_hex = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_hexdigit());
+    // iOS identifierForVendor is a dashed uuid, like device profiles make
+    let is_uuid =
+        device_id.split('-').map(str::len).eq([8, 4, 4, 4, 12]) && device_id.split('-').all(is_hex);
+    if device_info.system_name.eq_ignore_ascii_case("ios") {
+        if !is_uuid && !is_hex(device_id) {
+            problems.push((
+                "device_id",
+                format!(
+                    "device_id \"{}\" is neither a uuid nor a hex string",
+                    device_id
+                ),
+            ));
+        }
+    } else if !is_hex(device_id) {
+        problems.push((
+            "device_id",
+            format!("device_id \"{}\" is not a hex string", device_id),
+        ));
+    }
+    let app_version = &device_info.app_version;
+    if !app_version
+        .split('.')
+        .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+    {
+        problems.push((
+            "app_version",
+            format!(
+                "app_version \"{}\" should be numeric, like 9.0.12",
+                app_version
+            ),
+        ));
+    }
+    if !(-90.0..=90.0).contains(&device_info.lat) {
+        problems.push((
+            "lat",
+            format!("lat {} is not between -90 and 90", device_info.lat),
+        ));
+    }
+    if !(-180.0..=180.0).contains(&device_info.lon) {
+        problems.push((
+            "lon",
+            format!("lon {} is not between -180 and 180", device_info.lon),
+        ));
+    }
+    problems
+}
+
+fn check_schedules(user: &User) -> Vec<(Vec<Segment>, String)> {
+    let mut problems = vec![];
+    let mut check = |schedule: &Option<Schedule>, mut path: Vec<Segment>| {
+        if let Some(schedule) = schedule {
+            if let Err(err) = schedule.parse_cron() {
+                path.push(Segment::Key("schedule".to_string()));
+                path.push(Segment::Key("cron".to_string()));
+                problems.push((path, err.to_string()));
+            }
+        }
+    };
+    check(&user.schedule, vec![]);
+    for (i, action) in user.actions.iter().enumerate() {
+        match action {
+            Action::CounselorFormFill(form_fill) => check(
+                &form_fill.schedule,
+                vec![Segment::Key("actions".to_string()), Segment::Index(i)],
+            ),
+        }
+    }
+    problems
+}
+
+fn to_segments(path: &serde_path_to_error::Path) -> Vec<Segment> {
+    let mut segments = vec![];
+    for segment in path.iter() {
+        match segment {
+            serde_path_to_error::Segment::Seq { index } => segments.push(Segment::Index(*index)),
+            serde_path_to_error::Segment::Map { key } => segments.push(Segment::Key(key.clone())),
+            serde_path_to_error::Segment::Enum { .. } => {}
+            serde_path_to_error::Segment::Unknown => break,
+        }
+    }
+    segments
+}
+
+struct Source {
+    file: PathBuf,
+    spans: Spans,
+}
+
+// a document node that may define the fields of a user
+type Location = (usize, Vec<Segment>);
+
+#[derive(Default)]
+struct Validator {
+    sources: Vec<Source>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Validator {
+    fn push(&mut self, level: Level, file: &Path, line: Option<usize>, message: String) {
+        let diagnostic = Diagnostic {
+            level,
+            file: file.to_owned(),
+            line,
+            message,
+        };
+        // an inherited mistake shows up once, not once per user
+        if !self.diagnostics.contains(&diagnostic) {
+            self.diagnostics.push(diagnostic);
+        }
+    }
+
+    fn push_at(&mut self, level: Level, at: (PathBuf, Option<usize>), message: String) {
+        self.push(level, &at.0, at.1, message);
+    }
+
+    // where `path` of a user was written: the user itself, the templates it
+    // extends, the defaults, or else the closest parent within the user
+    fn locate(&self, candidates: &[Location], path: &[Segment]) -> (PathBuf, Option<usize>) {
+        for (source, prefix) in candidates {
+            let full: Vec<Segment> = prefix.iter().chain(path).cloned().collect();
+            if let Some(line) = self.sources[*source].spans.get(&full) {
+                return (self.sources[*source].file.clone(), Some(line));
+            }
+        }
+        let (source, prefix) = &candidates[0];
+        let source = &self.sources[*source];
+        let line = (0..=path.len()).rev().find_map(|len| {
+            let full: Vec<Segment> = prefix.iter().chain(&path[..len]).cloned().collect();
+            source.spans.get(&full)
+        });
+        (source.file.clone(), line)
+    }
+}
+
+fn template_chain(templates: &Mapping, user: &Value) -> Vec<String> {
+    let mut chain: Vec<String> = vec![];
+    let mut current = user;
+    while let Some(name) = current.get("extends").and_then(|v| v.as_str()) {
+        if chain.iter().any(|c| c == name) {
+            break;
+        }
+        chain.push(name.to_owned());
+        match templates.get(&key(name)) {
+            Some(template) => current = template,
+            None => break,
+        }
+    }
+    chain
+}
+
+pub fn validate(path: &str) -> Vec<Diagnostic> {
+    let path = Path::new(path);
+    let is_dir = path.is_dir();
+    let mut validator = Validator::default();
+
+    let files = if is_dir {
+        match list_config_dir(path) {
+            Ok(files) => files,
+            Err(err) => {
+                validator.push(Level::Error, path, None, format!("{:#}", err));
+                return validator.diagnostics;
+            }
+        }
+    } else {
+        vec![path.to_owned()]
+    };
+
+    let mut passphrase = None;
+    let mut root: Option<(usize, Mapping)> = None;
+    let mut entries: Vec<(Location, Value)> = vec![];
+    for file in files {
+        let contents = match read_config_file(&file, &mut passphrase) {
+            Ok(contents) => contents,
+            Err(err) => {
+                validator.push(Level::Error, &file, None, format!("{:#}", err));
+                continue;
+            }
+        };
+        let value: Value = match serde_yaml::from_slice(&contents) {
+            Ok(value) => value,
+            Err(err) => {
+                let line = err.location().map(|location| location.line());
+                validator.push(Level::Error, &file, line, err.to_string());
+                continue;
+            }
+        };
+        let source = validator.sources.len();
+        validator.sources.push(Source {
+            file: file.clone(),
+            spans: Spans::parse(&String::from_utf8_lossy(&contents)),
+        });
+        let users_line = validator.sources[source]
+            .spans
+            .get(&[Segment::Key("users".to_string())]);
+
+        if is_dir && !is_defaults_file(&file) {
+            match value {
+                Value::Sequence(users) => {
+                    for (i, user) in users.into_iter().enumerate() {
+                        entries.push(((source, vec![Segment::Index(i)]), user));
+                    }
+                }
+                Value::Null => {}
+                user => entries.push(((source, vec![]), user)),
+            }
+            continue;
+        }
+
+        let mut mapping = match value {
+            Value::Mapping(mapping) => mapping,
+            Value::Null if is_dir => Mapping::new(),
+            _ => {
+                validator.push(
+                    Level::Error,
+                    &file,
+                    None,
+                    "the config must be a mapping".to_string(),
+                );
+                continue;
+            }
+        };
+        match mapping.remove(&key("users")) {
+            Some(_) if is_dir => validator.push(
+                Level::Error,
+                &file,
+                users_line,
+                "users go into their own files in a config directory".to_string(),
+            ),
+            Some(Value::Sequence(users)) => {
+                for (i, user) in users.into_iter().enumerate() {
+                    entries.push((
+                        (
+                            source,
+                            vec![Segment::Key("users".to_string()), Segment::Index(i)],
+                        ),
+                        user,
+                    ));
+                }
+            }
+            Some(_) => validator.push(
+                Level::Error,
+                &file,
+                users_line,
+                "users must be a list".to_string(),
+            ),
+            None if is_dir => {}
+            None => validator.push(
+                Level::Error,
+                &file,
+                None,
+                "missing field `users`".to_string(),
+            ),
+        }
+        root = Some((source, mapping));
+    }
+
+    // session_dir, defaults and templates
+    let (root_source, root) = match root {
+        Some((source, root)) => (Some(source), root),
+        None => (None, Mapping::new()),
+    };
+    let mut root_value = root.clone();
+    root_value.insert(key("users"), Value::Sequence(vec![]));
+    let root_value = Value::Mapping(root_value);
+    if let Some(source) = root_source {
+        let candidates = [(source, vec![])];
+        match serde_path_to_error::deserialize::<_, Config>(root_value.clone()) {
+            Ok(config) => {
+                let mut unknown = vec![];
+                let known = serde_yaml::to_value(&config).unwrap();
+                find_unknown_keys(&root_value, &known, &mut vec![], &mut unknown);
+                for path in unknown {
+                    let at = validator.locate(&candidates, &path);
+                    validator.push_at(
+                        Level::Error,
+                        at,
+                        format!("unknown field `{}`", display_path(&path)),
+                    );
+                }
+            }
+            Err(err) => {
+                let at = validator.locate(&candidates, &to_segments(err.path()));
+                validator.push_at(Level::Error, at, err.inner().to_string());
+            }
+        }
+    }
+
+    let templates = match root.get(&key("templates")) {
+        Some(Value::Mapping(templates)) => templates.clone(),
+        _ => Mapping::new(),
+    };
+    let mut seen: HashMap<(String, String), (PathBuf, Option<usize>)> = HashMap::new();
+    for (entry, user) in entries {
+        let mut candidates = vec![entry.clone()];
+        if let Some(source) = root_source {
+            for name in template_chain(&templates, &user) {
+                candidates.push((
+                    source,
+                    vec![Segment::Key("templates".to_string()), Segment::Key(name)],
+                ));
+            }
+            candidates.push((source, vec![Segment::Key("defaults".to_string())]));
+        }
+        let entry_at = validator.locate(&candidates[..1], &[]);
+
+        let mut single = root.clone();
+        single.insert(key("users"), Value::Sequence(vec![user]));
+        let mut single = Value::Mapping(single);
+        if let Err(err) = templates::expand(&mut single) {
+            validator.push_at(Level::Error, entry_at, err.root_cause().to_string());
+            continue;
+        }
+        let user_value = single["users"][0].clone();
+
+        let user: User = match serde_path_to_error::deserialize(user_value.clone()) {
+            Ok(user) => user,
+            Err(err) => {
+                let at = validator.locate(&candidates, &to_segments(err.path()));
+                validator.push_at(Level::Error, at, err.inner().to_string());
+                continue;
+            }
+        };
+
+        let mut unknown = vec![];
+        let known = serde_yaml::to_value(&user).unwrap();
+        find_unknown_keys(&user_value, &known, &mut vec![], &mut unknown);
+        for path in unknown {
+            let at = validator.locate(&candidates, &path);
+            validator.push_at(
+                Level::Error,
+                at,
+                format!("unknown field `{}`", display_path(&path)),
+            );
+        }
+
+        for (field, message) in check_device_info(&user.device_info) {
+            let at = validator.locate(
+                &candidates,
+                &[
+                    Segment::Key("device_info".to_string()),
+                    Segment::Key(field.to_string()),
+                ],
+            );
+            validator.push_at(Level::Error, at, message);
+        }
+
+        for (path, message) in check_schedules(&user) {
+            let at = validator.locate(&candidates, &path);
+            validator.push_at(Level::Error, at, message);
+        }
+
+        let id = (user.school.clone(), user.username.clone());
+        match seen.get(&id) {
+            Some((file, line)) => {
+                let first = match line {
+                    Some(line) => format!("{}:{}", file.display(), line),
+                    None => file.display().to_string(),
+                };
+                validator.push_at(
+                    Level::Warning,
+                    entry_at,
+                    format!(
+                        "duplicate user {}:{}, first defined at {}",
+                        id.0, id.1, first
+                    ),
+                );
+            }
+            None => {
+                seen.insert(id, entry_at);
+            }
+        }
+    }
+
+    validator.diagnostics
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use super::{check_device_info, check_schedules, validate, Level, Segment, Spans};
+    use crate::config::DeviceInfo;
+
+    #[test]
+    fn test_spans() {
+        let spans = Spans::parse(
+            "users:\n  - school: a\n    actions:\n      - type: X\n        form_data: [{question: q}]\n",
+        );
+        let users = Segment::Key("users".to_string());
+        assert_eq!(spans.get(std::slice::from_ref(&users)), Some(1));
+        assert_eq!(spans.get(&[users.clone(), Segment::Index(0)]), Some(2));
+        assert_eq!(
+            spans.get(&[
+                users.clone(),
+                Segment::Index(0),
+                Segment::Key("actions".to_string()),
+                Segment::Index(0),
+                Segment::Key("form_data".to_string()),
+                Segment::Index(0),
+                Segment::Key("question".to_string()),
+            ]),
+            Some(5)
+        );
+    }
+
+    #[test]
+    fn test_check_device_info() {
+        let mut device_info = DeviceInfo {
+            model: "Galaxy Nexus".to_string(),
+            app_version: "9.0.12".to_string(),
+            system_version: "11.0.0".to_string(),
+            system_name: "android".to_string(),
+            device_id: "0123456789abcdef".to_string(),
+            lat: 36.123456,
+            lon: 120.789012,
+            user_agent: "okhttp/3.12.4".to_string(),
+        };
+        assert!(check_device_info(&device_info).is_empty());
+        device_info.app_version = "9.0.12-beta".to_string();
+        device_info.device_id = "<random 16 byte hex>".to_string();
+        device_info.lat = 120.789012;
+        let fields: Vec<&str> = check_device_info(&device_info)
+            .into_iter()
+            .map(|(field, _)| field)
+            .collect();
+        assert_eq!(fields, vec!["device_id", "app_version", "lat"]);
+
+        device_info.app_version = "9.0.12".to_string();
+        device_info.lat = 36.123456;
+        device_info.system_name = "iOS".to_string();
+        device_info.device_id = "A39EB489-A530-5CC3-3B0B-F77683AEB68E".to_string();
+        assert!(check_device_info(&device_info).is_empty());
+        device_info.system_name = "android".to_string();
+        assert_eq!(check_device_info(&device_info).len(), 1);
+    }
+
+    #[test]
+    fn test_validate_device_profile() {
+        let path = env::temp_dir().join(format!("config-{}.yml", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"users:
+  - school: "10000"
+    username: alice
+    password: hunter2
+    address: somewhere
+    device_profile: iphone-12
+    device_info:
+      lat: 36.1
+      lon: 120.7
+    actions: []
+  - school: "10000"
+    username: bob
+    password: hunter2
+    address: somewhere
+    device_profile: auto
+    device_info:
+      lat: 36.1
+      lon: 120.7
+    actions: []
+"#,
+        )
+        .unwrap();
+
+        let diagnostics = validate(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+    }
+
+    #[test]
+    fn test_validate() {
+        let path = env::temp_dir().join(format!("config-{}.yml", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"session_dir: sessions
+defaults:
+  address: 默认地址
+  device_info:
+    model: Galaxy Nexus
+    app_version: "9.0.12"
+    system_version: "11.0.0"
+    system_name: android
+    device_id: "0123456789abcdef"
+    lat: 36.123456
+    lon: 200
+    user_agent: okhttp/3.12.4
+  actions: []
+users:
+  - school: "10000"
+    username: alice
+    pasword: hunter2
+  - school: "10000"
+    username: bob
+    actions:
+      - type: CounselorFormFil
+        force_submit: false
+        form_data: []
+  - school: "10000"
+    username: alice
+"#,
+        )
+        .unwrap();
+
+        let diagnostics = validate(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let lines: Vec<(Level, Option<usize>, &str)> = diagnostics
+            .iter()
+            .map(|d| (d.level, d.line, d.message.as_str()))
+            .collect();
+        assert_eq!(
+            lines[0],
+            (Level::Error, Some(17), "unknown field `pasword`")
+        );
+        assert_eq!(
+            lines[1],
+            (
+                Level::Error,
+                Some(11),
+                "lon 200 is not between -180 and 180"
+            )
+        );
+        assert_eq!(lines[2].0, Level::Error);
+        assert_eq!(lines[2].1, Some(21));
+        assert!(lines[2].2.starts_with("unknown variant `CounselorFormFil`"));
+        assert_eq!(lines[3].0, Level::Warning);
+        assert_eq!(lines[3].1, Some(24));
+        assert_eq!(
+            diagnostics[3].to_string(),
+            format!(
+                "{}:24: warning: duplicate user 10000:alice, first defined at {}:15",
+                path.display(),
+                path.display()
+            )
+        );
+        assert_eq!(lines.len(), 4);
+        assert_eq!(diagnostics[0].file, PathBuf::from(&path));
+    }
+
+    #[test]
+    fn test_check_schedules() {
+        let mut user = crate::config::test_user("10000", "alice");
+        user.schedule = Some(serde_yaml::from_str("cron: \"30 7 * * *\"").unwrap());
+        user.actions = serde_yaml::from_str(
+            r#"- type: CounselorFormFill
+  force_submit: false
+  form_data: []
+  schedule:
+    cron: "every morning"
+"#,
+        )
+        .unwrap();
+        let problems = check_schedules(&user);
+        assert_eq!(problems.len(), 1);
+        assert_eq!(
+            problems[0].0,
+            vec![
+                Segment::Key("actions".to_string()),
+                Segment::Index(0),
+                Segment::Key("schedule".to_string()),
+                Segment::Key("cron".to_string()),
+            ]
+        );
+        assert!(problems[0]
+            .1
+            .starts_with("Invalid cron expression \"every morning\""));
+    }
+}
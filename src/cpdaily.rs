@@ -1,6 +1,9 @@
+pub mod app_version;
 pub mod client;
 pub mod crypto;
 pub mod loginprovider;
+pub mod nonblocking;
+pub mod session;
 pub mod structs;
 
 use anyhow::Result;
@@ -9,19 +12,18 @@ use std::str;
 
 use self::structs::tenants::Tenant;
 
+const TENANT_LIST_URL: &str = "https://mobile.campushoy.com/v6/config/guest/tenant/list";
+
+fn parse_tenant_list(response: Value) -> Result<Vec<Tenant>> {
+    let data = response
+        .get("data")
+        .ok_or_else(|| anyhow::anyhow!("No data in the tenant list response"))?;
+    Ok(serde_json::from_value(data.to_owned())?)
+}
+
 pub fn get_all_tenants() -> Result<Vec<Tenant>> {
-    let response: Value = client::unauth()?
-        .get("https://mobile.campushoy.com/v6/config/guest/tenant/list")
-        .send()?
-        .json()?;
-    let tenants: Vec<Tenant> = serde_json::from_value(
-        response
-            .get("data")
-            .expect("Retrieving data from tenant list response")
-            .to_owned(),
-    )
-    .expect("Parsing tenant list");
-    Ok(tenants)
+    let response: Value = client::unauth()?.get(TENANT_LIST_URL).send()?.json()?;
+    parse_tenant_list(response)
 }
 
 pub fn match_school_from_tenant_list<'a>(
@@ -38,10 +40,16 @@ pub fn match_school_from_tenant_list<'a>(
 
 #[cfg(test)]
 mod tests {
+    use std::env;
+
     use crate::cpdaily;
 
     #[test]
     fn test_get_all_tenants() {
+        if env::var_os("CPDAILY_NETWORK_TESTS").is_none() {
+            println!("CPDAILY_NETWORK_TESTS is not set. Skipping...");
+            return;
+        }
         let tenant_list = cpdaily::get_all_tenants();
         tenant_list.expect("Fetching tenant list");
     }
@@ -0,0 +1,83 @@
+use std::fs;
+
+use anyhow::anyhow;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::cpdaily::client;
+
+fn check_version(version: &str) -> anyhow::Result<String> {
+    let version = version.trim();
+    if version.is_empty()
+        || !version
+            .split('.')
+            .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()))
+    {
+        return Err(anyhow!("\"{}\" is not an app version", version));
+    }
+    Ok(version.to_owned())
+}
+
+// the body is either the version itself, or json with the version at `pointer`
+fn parse_response(body: &str, pointer: Option<&str>) -> anyhow::Result<String> {
+    match pointer {
+        Some(pointer) => {
+            let result: Value = serde_json::from_str(body)?;
+            let version = result
+                .pointer(pointer)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| anyhow!("No app version at {} of the response", pointer))?;
+            check_version(version)
+        }
+        None => check_version(body),
+    }
+}
+
+pub fn fetch(url: &str, pointer: Option<&str>) -> anyhow::Result<String> {
+    let response = client::unauth()?.get(url).send()?.error_for_status()?;
+    parse_response(&response.text()?, pointer)
+}
+
+pub fn read_file(path: &str) -> anyhow::Result<String> {
+    check_version(&fs::read_to_string(path)?)
+}
+
+pub fn rewrite_user_agent(user_agent: &str, version: &str) -> String {
+    Regex::new(r"\b(cpdaily|wisedu)/[\d.]+")
+        .unwrap()
+        .replace_all(user_agent, |c: &regex::Captures| {
+            format!("{}/{}", &c[1], version)
+        })
+        .into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_response, rewrite_user_agent};
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(parse_response("9.2.1\n", None).unwrap(), "9.2.1");
+        assert_eq!(
+            parse_response(
+                r#"{"resultCount":1,"results":[{"version":"9.2.1"}]}"#,
+                Some("/results/0/version")
+            )
+            .unwrap(),
+            "9.2.1"
+        );
+        assert!(parse_response("<html></html>", None).is_err());
+        assert!(parse_response(r#"{"data":{}}"#, Some("/data/version")).is_err());
+    }
+
+    #[test]
+    fn test_rewrite_user_agent() {
+        assert_eq!(
+            rewrite_user_agent(
+                "Mozilla/5.0 (Linux; Android 8.0.0; MI 6 Build/OPR1.170623.027; wv) okhttp/3.12.4 cpdaily/9.0.12 wisedu/9.0.12",
+                "9.2.1"
+            ),
+            "Mozilla/5.0 (Linux; Android 8.0.0; MI 6 Build/OPR1.170623.027; wv) okhttp/3.12.4 cpdaily/9.2.1 wisedu/9.2.1"
+        );
+    }
+}
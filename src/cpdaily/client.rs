@@ -1,10 +1,14 @@
 use crate::config::User;
 use crate::cpdaily::crypto::ciphers::{base64, des};
+use crate::cpdaily::session::CookieJar;
 use cached::proc_macro::cached;
 use reqwest::blocking::ClientBuilder;
 use reqwest::header::{HeaderMap, HeaderValue};
+use std::sync::Arc;
 
-pub fn new(user: &User) -> anyhow::Result<reqwest::blocking::Client> {
+const UNAUTH_USER_AGENT: &str = "Mozilla/5.0 (Linux; U; Android 8.1.0; zh-cn; BLA-AL00 Build/HUAWEIBLA-AL00) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/57.0.2987.132 MQQBrowser/8.9 Mobile Safari/537.36";
+
+fn user_headers(user: &User) -> anyhow::Result<HeaderMap> {
     let mut headers = HeaderMap::new();
     headers.insert("clientType", HeaderValue::from_static("cpdaily_student"));
     headers.insert("deviceType", HeaderValue::from_static("1"));
@@ -18,18 +22,41 @@ pub fn new(user: &User) -> anyhow::Result<reqwest::blocking::Client> {
             None,
         )?))?,
     );
+    Ok(headers)
+}
+
+pub fn new(user: &User, cookie_jar: Arc<CookieJar>) -> anyhow::Result<reqwest::blocking::Client> {
     Ok(ClientBuilder::new()
         .user_agent(&user.device_info.user_agent)
-        .default_headers(headers)
+        .default_headers(user_headers(user)?)
         .redirect(reqwest::redirect::Policy::none())
-        .cookie_store(true)
+        .cookie_provider(cookie_jar)
         .build()?)
 }
 
 #[cached(name = "CLIENT_UNAUTH", result = true)]
 pub fn unauth() -> Result<reqwest::blocking::Client, reqwest::Error> {
     ClientBuilder::new()
-        .user_agent("Mozilla/5.0 (Linux; U; Android 8.1.0; zh-cn; BLA-AL00 Build/HUAWEIBLA-AL00) AppleWebKit/537.36 (KHTML, like Gecko) Version/4.0 Chrome/57.0.2987.132 MQQBrowser/8.9 Mobile Safari/537.36")
+        .user_agent(UNAUTH_USER_AGENT)
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+}
+
+// the async clients send the same headers, and one sharing a cookie jar with
+// a blocking client sees the session it logged in
+pub fn new_async(user: &User, cookie_jar: Arc<CookieJar>) -> anyhow::Result<reqwest::Client> {
+    Ok(reqwest::ClientBuilder::new()
+        .user_agent(&user.device_info.user_agent)
+        .default_headers(user_headers(user)?)
+        .redirect(reqwest::redirect::Policy::none())
+        .cookie_provider(cookie_jar)
+        .build()?)
+}
+
+#[cached(name = "CLIENT_UNAUTH_ASYNC", result = true)]
+pub fn unauth_async() -> Result<reqwest::Client, reqwest::Error> {
+    reqwest::ClientBuilder::new()
+        .user_agent(UNAUTH_USER_AGENT)
         .redirect(reqwest::redirect::Policy::none())
         .build()
 }
@@ -1,12 +1,16 @@
+use openssl::base64::encode_block;
 use openssl::error::ErrorStack;
-
+use openssl::rand::rand_bytes;
 use openssl::symm::{decrypt as cipher_decrypt, encrypt as cipher_encrypt, Cipher};
 
+const AES_CHARS: &[u8] = b"ABCDEFGHJKMNPQRSTWXYZabcdefhijkmnprstwxyz2345678";
+
 pub fn encrypt(plaintext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, ErrorStack> {
     let cipher = Cipher::aes_128_cbc();
     cipher_encrypt(cipher, key, Some(iv), plaintext)
 }
 
+#[allow(dead_code)]
 pub fn decrypt(ciphertext: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, ErrorStack> {
     let cipher = Cipher::aes_128_cbc();
     cipher_decrypt(cipher, key, Some(iv), ciphertext)
@@ -29,9 +33,27 @@ pub fn getak(key: &str, hk: &str) -> String {
     out
 }
 
+fn random_string(len: usize) -> Result<String, ErrorStack> {
+    let mut buf = vec![0; len];
+    rand_bytes(&mut buf)?;
+    Ok(buf
+        .iter()
+        .map(|b| AES_CHARS[*b as usize % AES_CHARS.len()] as char)
+        .collect())
+}
+
+// encryptAES() from the authserver login page: 64 random chars are prepended
+// to the password, and the salt is used as the key with a random 16-char iv
+pub fn encrypt_password(password: &str, salt: &str) -> Result<String, ErrorStack> {
+    let iv = random_string(16)?;
+    let plaintext = format!("{}{}", random_string(64)?, password);
+    let ciphertext = encrypt(plaintext.as_bytes(), salt.trim().as_bytes(), iv.as_bytes())?;
+    Ok(encode_block(&ciphertext))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::cpdaily::crypto::ciphers::aes::{decrypt, encrypt, getak};
+    use crate::cpdaily::crypto::ciphers::aes::{decrypt, encrypt, encrypt_password, getak};
     use crate::cpdaily::crypto::ciphers::base64::decode;
 
     #[test]
@@ -56,4 +78,16 @@ mod tests {
             encrypt(clear_text.as_bytes(), key, iv).unwrap()
         );
     }
+
+    #[test]
+    fn test_encrypt_password() {
+        let salt = "6044cb8792f0452c";
+        let encrypted = encrypt_password("p@ssw0rd", salt).unwrap();
+        assert_ne!(encrypted, encrypt_password("p@ssw0rd", salt).unwrap());
+        // the iv is random, but only the first block depends on it
+        let ciphertext = decode(&encrypted).unwrap();
+        let cleartext = decrypt(&ciphertext, salt.as_bytes(), &[0; 16]).unwrap();
+        assert_eq!(cleartext.len(), 64 + "p@ssw0rd".len());
+        assert_eq!(&cleartext[64..], b"p@ssw0rd");
+    }
 }
@@ -1,5 +1,8 @@
 use openssl::{
+    base64::decode_block,
+    bn::BigNum,
     error::ErrorStack,
+    pkey::Public,
     rsa::{Padding, Rsa},
 };
 
@@ -27,8 +30,29 @@ qcE2sdGKsNXswpIASU6maSxia2scPNx1smKS0FWlBf61Bst4CEWbyQJAZT69Xm1D
 QbHI5l5ymh+btw==
 -----END PRIVATE KEY-----";
 
+fn load_public_key(pem: &str) -> Result<Rsa<Public>, ErrorStack> {
+    // accept both "BEGIN PUBLIC KEY" and "BEGIN RSA PUBLIC KEY"
+    Rsa::public_key_from_pem(pem.as_bytes())
+        .or_else(|_| Rsa::public_key_from_pem_pkcs1(pem.as_bytes()))
+}
+
+// wrap a bare base64 DER key, as served by most login pages, into PEM
+pub fn public_key_from_base64(key: &str) -> Result<String, ErrorStack> {
+    let der = decode_block(&key.split_whitespace().collect::<String>())?;
+    let rsa = Rsa::public_key_from_der(&der).or_else(|_| Rsa::public_key_from_der_pkcs1(&der))?;
+    Ok(String::from_utf8(rsa.public_key_to_pem()?).unwrap())
+}
+
+pub fn public_key_from_components(modulus: &str, exponent: &str) -> Result<String, ErrorStack> {
+    let rsa = Rsa::from_public_components(
+        BigNum::from_hex_str(modulus)?,
+        BigNum::from_hex_str(exponent)?,
+    )?;
+    Ok(String::from_utf8(rsa.public_key_to_pem()?).unwrap())
+}
+
 pub fn public_encrypt(data: &str, key: Option<&str>) -> Result<Vec<u8>, ErrorStack> {
-    let rsa = Rsa::public_key_from_pem(key.unwrap_or(CPDAILY_RSA_PUBLIC).as_bytes())?;
+    let rsa = load_public_key(key.unwrap_or(CPDAILY_RSA_PUBLIC))?;
     let mut encrypted = vec![0; rsa.size() as usize];
     rsa.public_encrypt(data.as_bytes(), &mut encrypted, Padding::PKCS1)?;
     Ok(encrypted)
@@ -47,10 +71,34 @@ pub fn private_decrypt(data: &[u8], key: Option<&str>) -> Result<String, ErrorSt
 #[cfg(test)]
 mod tests {
     use crate::cpdaily::crypto::ciphers::base64::decode;
-    use crate::cpdaily::crypto::ciphers::rsa::private_decrypt;
+    use crate::cpdaily::crypto::ciphers::rsa::{
+        private_decrypt, public_encrypt, public_key_from_base64, public_key_from_components,
+    };
+    use openssl::{base64::encode_block, rsa::Rsa};
 
     #[test]
     fn test_rsa_decrypt() {
         assert_eq!("4eb81128-4741-4879-b2cf-7079ed8c5d65|7Cf7my4F|7Llv2JZZ", private_decrypt(decode("sWBzAnDXCwawQ8V3qcXmG24HqHqPjRQwo98N2ADKGO2ghA37lveE+oirR0w7EubkGZx7bsi578P+gab8FUJEGPe/S8Bx1QCrWAbdEaeBFl6IEIuzWraxSBTguVAXtN0+9dh1w1rJK9Vkd1iLa72X233zCURdXLKhgb5zEpzpVok=").unwrap().as_slice(), None).unwrap());
     }
+
+    #[test]
+    fn test_rsa_arbitrary_key() {
+        let keypair = Rsa::generate(1024).unwrap();
+        let private = String::from_utf8(keypair.private_key_to_pem().unwrap()).unwrap();
+        let pkcs1 = String::from_utf8(keypair.public_key_to_pem_pkcs1().unwrap()).unwrap();
+        let der = encode_block(&keypair.public_key_to_der().unwrap());
+        let components = public_key_from_components(
+            &keypair.n().to_hex_str().unwrap(),
+            &keypair.e().to_hex_str().unwrap(),
+        )
+        .unwrap();
+
+        for key in [pkcs1, public_key_from_base64(&der).unwrap(), components] {
+            let encrypted = public_encrypt("p@ssw0rd", Some(&key)).unwrap();
+            assert_eq!(
+                "p@ssw0rd",
+                private_decrypt(&encrypted, Some(&private)).unwrap()
+            );
+        }
+    }
 }
@@ -30,34 +30,45 @@ struct SecretNoncePair {
     pub fhk: String,
 }
 
-fn fetch_first_v2_secrets() -> Result<GetSecretResponse, reqwest::Error> {
+const SECRET_URL: &str = "https://mobile.campushoy.com/app/auth/dynamic/secret/getSecretKey/v-8222";
+
+fn secret_request() -> serde_json::Value {
     let uuid = Uuid::new_v4();
-    let cleartext_p = format!("{}|first_v2", uuid.to_hyphenated().to_string());
+    let cleartext_p = format!("{}|first_v2", uuid.to_hyphenated());
     let ciphertext_p = rsa::public_encrypt(&cleartext_p, None).unwrap();
     let encoded_p = base64::encode(&ciphertext_p);
     let s = format!(
         "p={}&2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824",
         encoded_p
     );
+    json!({
+        "p": encoded_p,
+        "s": hash(&s).unwrap(),
+    })
+}
 
-    Ok(client::unauth()?
-        .post("https://mobile.campushoy.com/app/auth/dynamic/secret/getSecretKey/v-8222")
-        .json(&json!({
-            "p": encoded_p,
-            "s": hash(&s).unwrap(),
-        }))
+fn fetch_first_v2_secrets() -> Result<GetSecretResponse, reqwest::Error> {
+    client::unauth()?
+        .post(SECRET_URL)
+        .json(&secret_request())
         .send()?
-        .json::<GetSecretResponse>()?)
+        .json::<GetSecretResponse>()
 }
 
-fn extract_nonce_from_secret_response(response: GetSecretResponse) -> anyhow::Result<SecretNoncePair> {
-        // example: {"errCode":0,"errMsg":null,"data":"sWBzAnDXCwawQ8V3qcXmG24HqHqPjRQwo98N2ADKGO2ghA37lveE+oirR0w7EubkGZx7bsi578P+gab8FUJEGPe/S8Bx1QCrWAbdEaeBFl6IEIuzWraxSBTguVAXtN0+9dh1w1rJK9Vkd1iLa72X233zCURdXLKhgb5zEpzpVok="}
-    let encrypted_data = response.data.unwrap();
-    let raw_data =
-        rsa::private_decrypt(&base64::decode(&encrypted_data).unwrap(), None).unwrap();
+fn extract_nonce_from_secret_response(
+    response: GetSecretResponse,
+) -> anyhow::Result<SecretNoncePair> {
+    // example: {"errCode":0,"errMsg":null,"data":"sWBzAnDXCwawQ8V3qcXmG24HqHqPjRQwo98N2ADKGO2ghA37lveE+oirR0w7EubkGZx7bsi578P+gab8FUJEGPe/S8Bx1QCrWAbdEaeBFl6IEIuzWraxSBTguVAXtN0+9dh1w1rJK9Vkd1iLa72X233zCURdXLKhgb5zEpzpVok="}
+    let encrypted_data = response
+        .data
+        .ok_or_else(|| anyhow::anyhow!("No data in the secret response"))?;
+    let raw_data = rsa::private_decrypt(&base64::decode(&encrypted_data)?, None)?;
     let splits: Vec<&str> = raw_data.split('|').collect();
     if splits.len() != 3 {
-        return Err(anyhow::anyhow!("Unexpected number of splits in secret response: {}", splits.len()));
+        return Err(anyhow::anyhow!(
+            "Unexpected number of splits in secret response: {}",
+            splits.len()
+        ));
     }
     let chk = splits[1].to_string();
     let fhk = splits[2].to_string();
@@ -66,7 +77,11 @@ fn extract_nonce_from_secret_response(response: GetSecretResponse) -> anyhow::Re
 
 impl Local {
     fn from_server_response(response: GetSecretResponse) -> Self {
-        let nonce_pair = extract_nonce_from_secret_response(response).unwrap();
+        Local::try_from_server_response(response).unwrap()
+    }
+
+    fn try_from_server_response(response: GetSecretResponse) -> anyhow::Result<Self> {
+        let nonce_pair = extract_nonce_from_secret_response(response)?;
 
         crate::logger::log(sentry::Breadcrumb {
             category: Some("crypto.first_v2".to_string()),
@@ -87,11 +102,34 @@ impl Local {
             ..Default::default()
         });
 
-        Local { chk: nonce_pair.chk, fhk: nonce_pair.fhk }
+        Ok(Local {
+            chk: nonce_pair.chk,
+            fhk: nonce_pair.fhk,
+        })
+    }
+
+    // like new, but without blocking and with errors instead of panics
+    pub async fn fetch() -> anyhow::Result<Self> {
+        let secrets: GetSecretResponse = client::unauth_async()?
+            .post(SECRET_URL)
+            .json(&secret_request())
+            .send()
+            .await?
+            .json()
+            .await?;
+        if secrets.err_code != 0 {
+            return Err(anyhow::anyhow!(
+                "getSecret returns non-zero: {}",
+                secrets.err_msg.unwrap_or_else(|| "Unknown".to_string())
+            ));
+        }
+        Local::try_from_server_response(secrets)
     }
 }
 
 impl Local {
+    // fetches from the network, so no Default
+    #[allow(clippy::new_without_default)]
     pub fn new() -> Self {
         // fetch from getSecret
 
@@ -149,7 +187,11 @@ impl FirstV2 for Local {
 
 #[cfg(test)]
 mod tests {
-    use super::{GetSecretResponse, Local, fetch_first_v2_secrets, extract_nonce_from_secret_response};
+    use std::env;
+
+    use super::{
+        extract_nonce_from_secret_response, fetch_first_v2_secrets, GetSecretResponse, Local,
+    };
 
     #[test]
     fn test_parse_first_v2_get_secret_response() {
@@ -164,6 +206,10 @@ mod tests {
 
     #[test]
     fn test_fetch_first_v2_secrets() {
+        if env::var_os("CPDAILY_NETWORK_TESTS").is_none() {
+            println!("CPDAILY_NETWORK_TESTS is not set. Skipping...");
+            return;
+        }
         let secrets = fetch_first_v2_secrets().unwrap();
         assert_eq!(0, secrets.err_code);
         assert!(secrets.err_msg.is_none());
@@ -1,10 +1,348 @@
+use std::fmt;
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
 use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::cpdaily::crypto::ciphers::aes;
+use crate::cpdaily::structs::tenants::LoginProviderType;
 
+pub mod captcha;
 pub mod cas;
+pub mod detect;
 pub mod iap;
 pub mod rsa;
+pub mod sms;
 
 pub trait LoginProvider {
     fn get_type(&self) -> &'static str;
     fn login(&self, session: &Client, username: &str, password: &str) -> anyhow::Result<String>;
 }
+
+pub struct LoginOptions {
+    pub captcha_solver: Box<dyn captcha::CaptchaSolver>,
+    pub retry: RetryPolicy,
+    pub password_encryption: PasswordEncryption,
+    pub sms: Option<sms::SmsLogin>,
+}
+
+pub fn create(
+    login_type: LoginProviderType,
+    url: String,
+    service: Option<String>,
+    options: LoginOptions,
+) -> anyhow::Result<Box<dyn LoginProvider>> {
+    if options.sms.is_some() && login_type != LoginProviderType::Iap {
+        return Err(anyhow::anyhow!(
+            "SMS login is only supported by IAP, not {:?}",
+            login_type
+        ));
+    }
+    match login_type {
+        LoginProviderType::Iap => Ok(Box::new(iap::Iap {
+            url,
+            captcha_solver: options.captcha_solver,
+            retry: options.retry,
+            password_encryption: options.password_encryption,
+            sms: options.sms,
+        })),
+        LoginProviderType::Cas => Ok(Box::new(cas::Cas {
+            url,
+            service,
+            captcha_solver: options.captcha_solver,
+            password_encryption: options.password_encryption,
+        })),
+        // the adapter neither shows a captcha nor takes an AES password
+        LoginProviderType::Rsa if options.captcha_solver.get_type() != "Fail" => Err(
+            anyhow::anyhow!("Rsa login has no captcha, remove the captcha option"),
+        ),
+        LoginProviderType::Rsa if options.password_encryption != PasswordEncryption::Auto => Err(
+            anyhow::anyhow!("Rsa login encrypts passwords itself, remove password_encryption"),
+        ),
+        LoginProviderType::Rsa => Ok(Box::new(rsa::Rsa { url, service })),
+        LoginProviderType::Unknown => Err(anyhow::anyhow!("Unknown login provider for {}", url)),
+    }
+}
+
+// users may log in on several threads, only one of them asks on the terminal
+// at a time
+static PROMPT: Mutex<()> = Mutex::new(());
+
+pub fn lock_prompt() -> MutexGuard<'static, ()> {
+    PROMPT
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// run a user-supplied shell command, feeding it `input` and returning its trimmed stdout
+pub fn run_hook(command: &str, input: &[u8], envs: &[(&str, &str)]) -> anyhow::Result<String> {
+    let mut process = if cfg!(windows) {
+        let mut process = Command::new("cmd");
+        process.args(["/C", command]);
+        process
+    } else {
+        let mut process = Command::new("sh");
+        process.args(["-c", command]);
+        process
+    };
+    let mut child = process
+        .envs(envs.iter().copied())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow::anyhow!("Failed to open stdin of \"{}\"", command))?
+        .write_all(input)?;
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "\"{}\" exited with {}",
+            command,
+            output.status
+        ));
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_owned())
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum PasswordEncryption {
+    // encrypt only when the login page hands out a salt
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl PasswordEncryption {
+    pub fn apply(&self, password: &str, salt: Option<&str>) -> anyhow::Result<String> {
+        let salt = salt.filter(|salt| !salt.is_empty());
+        match (self, salt) {
+            (PasswordEncryption::Never, _) | (PasswordEncryption::Auto, None) => {
+                Ok(password.to_owned())
+            }
+            (PasswordEncryption::Always, None) => Err(anyhow::anyhow!(
+                "Password encryption is required but the login page gives no salt"
+            )),
+            (_, Some(salt)) => Ok(aes::encrypt_password(password, salt)?),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    #[serde(default = "default_retry_backoff_ms")]
+    pub backoff_ms: u64,
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_backoff_ms() -> u64 {
+    1000
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: default_retry_attempts(),
+            backoff_ms: default_retry_backoff_ms(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // linear backoff before the given (1-based) retry
+    pub fn backoff(&self, retry: u32) -> Duration {
+        Duration::from_millis(self.backoff_ms.saturating_mul(retry as u64))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoginError {
+    WrongPassword,
+    CaptchaRequired,
+    CaptchaMismatch,
+    AccountLocked(String),
+    StaleLt,
+    UnexpectedRedirect(String),
+    UnknownCode(String),
+}
+
+impl LoginError {
+    // classify the error text shown by CAS-like login pages
+    pub fn from_message(message: &str) -> Self {
+        if ["冻结", "锁定", "locked"]
+            .iter()
+            .any(|k| message.contains(k))
+        {
+            LoginError::AccountLocked(message.to_owned())
+        } else if ["验证码", "captcha"].iter().any(|k| message.contains(k)) {
+            LoginError::CaptchaMismatch
+        } else if ["密码", "password"].iter().any(|k| message.contains(k)) {
+            LoginError::WrongPassword
+        } else {
+            LoginError::UnknownCode(message.to_owned())
+        }
+    }
+
+    // whether trying again with the same credentials can succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, LoginError::CaptchaMismatch | LoginError::StaleLt)
+    }
+}
+
+impl fmt::Display for LoginError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoginError::WrongPassword => write!(f, "Wrong username or password"),
+            LoginError::CaptchaRequired => write!(f, "Captcha required"),
+            LoginError::CaptchaMismatch => write!(f, "Captcha mismatch"),
+            LoginError::AccountLocked(message) => write!(f, "Account locked: {}", message),
+            LoginError::StaleLt => write!(f, "Stale lt"),
+            LoginError::UnexpectedRedirect(url) => write!(f, "Unexpected redirect to {}", url),
+            LoginError::UnknownCode(code) => write!(f, "Unknown login result: {}", code),
+        }
+    }
+}
+
+impl std::error::Error for LoginError {}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{captcha, create, sms, LoginError, LoginOptions, PasswordEncryption, RetryPolicy};
+    use crate::cpdaily::structs::tenants::LoginProviderType;
+
+    #[test]
+    fn test_login_error_from_message() {
+        assert_eq!(
+            LoginError::from_message("您提供的用户名或者密码有误"),
+            LoginError::WrongPassword
+        );
+        assert_eq!(
+            LoginError::from_message("验证码错误"),
+            LoginError::CaptchaMismatch
+        );
+        assert_eq!(
+            LoginError::from_message("密码错误次数过多，账号已冻结"),
+            LoginError::AccountLocked("密码错误次数过多，账号已冻结".to_string())
+        );
+        assert_eq!(
+            LoginError::from_message("系统繁忙"),
+            LoginError::UnknownCode("系统繁忙".to_string())
+        );
+        assert!(!LoginError::WrongPassword.is_retryable());
+        assert!(LoginError::StaleLt.is_retryable());
+    }
+
+    #[test]
+    fn test_retry_policy() {
+        let policy: RetryPolicy = serde_yaml::from_str("attempts: 5").unwrap();
+        assert_eq!(policy.attempts, 5);
+        assert_eq!(policy.backoff_ms, 1000);
+        assert_eq!(policy.backoff(2), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_password_encryption() {
+        let salt = Some("6044cb8792f0452c");
+        assert_eq!(
+            PasswordEncryption::Auto.apply("p@ssw0rd", None).unwrap(),
+            "p@ssw0rd"
+        );
+        assert_eq!(
+            PasswordEncryption::Auto
+                .apply("p@ssw0rd", Some(""))
+                .unwrap(),
+            "p@ssw0rd"
+        );
+        assert_eq!(
+            PasswordEncryption::Never.apply("p@ssw0rd", salt).unwrap(),
+            "p@ssw0rd"
+        );
+        assert_ne!(
+            PasswordEncryption::Auto.apply("p@ssw0rd", salt).unwrap(),
+            "p@ssw0rd"
+        );
+        assert!(PasswordEncryption::Always.apply("p@ssw0rd", None).is_err());
+    }
+
+    #[test]
+    fn test_create_rejects_unsupported_options() {
+        let options = || LoginOptions {
+            captcha_solver: Box::new(captcha::Fail),
+            retry: RetryPolicy::default(),
+            password_encryption: PasswordEncryption::Auto,
+            sms: None,
+        };
+        let url = || "https://example.com/authserver".to_string();
+        let sms = || {
+            Some(sms::SmsLogin {
+                mobile: "13800000000".to_string(),
+                code_reader: Box::new(sms::Prompt),
+            })
+        };
+
+        for login_type in [
+            LoginProviderType::Iap,
+            LoginProviderType::Cas,
+            LoginProviderType::Rsa,
+        ] {
+            assert!(create(login_type, url(), None, options()).is_ok());
+        }
+        assert!(create(
+            LoginProviderType::Iap,
+            url(),
+            None,
+            LoginOptions {
+                sms: sms(),
+                ..options()
+            }
+        )
+        .is_ok());
+        assert!(create(
+            LoginProviderType::Cas,
+            url(),
+            None,
+            LoginOptions {
+                sms: sms(),
+                ..options()
+            }
+        )
+        .is_err());
+        assert!(create(
+            LoginProviderType::Rsa,
+            url(),
+            None,
+            LoginOptions {
+                sms: sms(),
+                ..options()
+            }
+        )
+        .is_err());
+
+        let prompt = LoginOptions {
+            captcha_solver: Box::new(captcha::Prompt {
+                path: "captcha.jpg".to_string(),
+            }),
+            ..options()
+        };
+        assert!(create(LoginProviderType::Rsa, url(), None, prompt).is_err());
+        let never = LoginOptions {
+            password_encryption: PasswordEncryption::Never,
+            ..options()
+        };
+        assert!(create(LoginProviderType::Rsa, url(), None, never).is_err());
+    }
+}
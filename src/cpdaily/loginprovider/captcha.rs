@@ -0,0 +1,108 @@
+use std::fs;
+use std::io::{self, Write};
+
+use super::{lock_prompt, run_hook, LoginError};
+
+pub trait CaptchaSolver {
+    fn get_type(&self) -> &'static str;
+    fn solve(&self, image: &[u8]) -> anyhow::Result<String>;
+}
+
+// gives up as soon as a captcha shows up.
+pub struct Fail;
+
+impl CaptchaSolver for Fail {
+    fn get_type(&self) -> &'static str {
+        "Fail"
+    }
+
+    fn solve(&self, _image: &[u8]) -> anyhow::Result<String> {
+        Err(LoginError::CaptchaRequired.into())
+    }
+}
+
+// saves the image to disk and asks for the answer on the terminal.
+pub struct Prompt {
+    pub path: String,
+}
+
+impl CaptchaSolver for Prompt {
+    fn get_type(&self) -> &'static str {
+        "Prompt"
+    }
+
+    fn solve(&self, image: &[u8]) -> anyhow::Result<String> {
+        // users share the default path, so hold the terminal from writing
+        // the image until it is removed again
+        let _prompt = lock_prompt();
+        fs::write(&self.path, image)?;
+        print!("Captcha saved to {}, please enter the answer: ", &self.path);
+        io::stdout().flush()?;
+
+        let mut answer = String::new();
+        io::stdin().read_line(&mut answer)?;
+        let _ = fs::remove_file(&self.path);
+
+        let answer = answer.trim();
+        if answer.is_empty() {
+            return Err(LoginError::CaptchaRequired.into());
+        }
+        Ok(answer.to_owned())
+    }
+}
+
+// pipes the image into an external command and reads the answer from its stdout.
+pub struct Command {
+    pub command: String,
+}
+
+impl CaptchaSolver for Command {
+    fn get_type(&self) -> &'static str {
+        "Command"
+    }
+
+    fn solve(&self, image: &[u8]) -> anyhow::Result<String> {
+        let answer = run_hook(&self.command, image, &[])?;
+        if answer.is_empty() {
+            return Err(LoginError::CaptchaRequired.into());
+        }
+        Ok(answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaptchaSolver, Command, Fail, LoginError};
+
+    #[test]
+    fn test_fail_solver() {
+        let err = Fail.solve(b"image").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LoginError>(),
+            Some(&LoginError::CaptchaRequired)
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_solver() {
+        let solver = Command {
+            command: "tr a-z A-Z".to_string(),
+        };
+        assert_eq!(solver.solve(b"abcd\n").unwrap(), "ABCD");
+
+        let solver = Command {
+            command: "cat > /dev/null; exit 1".to_string(),
+        };
+        assert!(solver.solve(b"abcd").is_err());
+
+        let solver = Command {
+            command: "cat > /dev/null".to_string(),
+        };
+        let err = solver.solve(b"abcd").unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<LoginError>(),
+            Some(&LoginError::CaptchaRequired)
+        );
+    }
+}
@@ -1,19 +1,278 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use regex::Regex;
 use reqwest::blocking::Client;
+use serde_json::Value;
 
-use super::LoginProvider;
+use super::{captcha::CaptchaSolver, LoginError, LoginProvider, PasswordEncryption};
 
 pub struct Cas {
     pub url: String,
+    pub service: Option<String>,
+    pub captcha_solver: Box<dyn CaptchaSolver>,
+    pub password_encryption: PasswordEncryption,
+}
+
+#[derive(PartialEq, Debug)]
+struct LoginForm {
+    pub lt: String,
+    pub execution: String,
+    pub event_id: String,
+    pub encrypt_salt: Option<String>,
+}
+
+impl LoginForm {
+    fn parse(html: &str) -> anyhow::Result<Self> {
+        let input_re = Regex::new(r#"(?is)<input\b[^>]*>"#).unwrap();
+        let attr_re = Regex::new(r#"(?is)([\w-]+)\s*=\s*["']([^"']*)["']"#).unwrap();
+
+        // the page may carry several forms (password, sms, ...) sharing the
+        // same hidden fields, so the first occurrence wins
+        let mut fields: HashMap<String, String> = HashMap::new();
+        for input in input_re.find_iter(html) {
+            let attrs: HashMap<String, String> = attr_re
+                .captures_iter(input.as_str())
+                .map(|c| (c[1].to_lowercase(), c[2].to_string()))
+                .collect();
+            let value = attrs.get("value").cloned().unwrap_or_default();
+            for key in ["name", "id"] {
+                if let Some(k) = attrs.get(key) {
+                    fields.entry(k.clone()).or_insert_with(|| value.clone());
+                }
+            }
+        }
+
+        let execution = fields
+            .get("execution")
+            .cloned()
+            .ok_or_else(|| anyhow!("execution not found on login page"))?;
+
+        // newer pages put the salt in a hidden input, older ones in a script
+        let encrypt_salt = fields
+            .get("pwdEncryptSalt")
+            .cloned()
+            .or_else(|| {
+                Regex::new(r#"pwdDefaultEncryptSalt\s*=\s*["']([^"']*)["']"#)
+                    .unwrap()
+                    .captures(html)
+                    .map(|c| c[1].to_string())
+            })
+            .filter(|salt| !salt.is_empty());
+
+        Ok(LoginForm {
+            lt: fields.get("lt").cloned().unwrap_or_default(),
+            execution,
+            event_id: fields
+                .get("_eventId")
+                .cloned()
+                .unwrap_or_else(|| "submit".to_string()),
+            encrypt_salt,
+        })
+    }
+}
+
+fn find_error_message(html: &str) -> Option<String> {
+    let tag_re = Regex::new(r"(?s)<[^>]*>").unwrap();
+    ["showErrorTip", "msg", "errorMsg"].iter().find_map(|id| {
+        Regex::new(&format!(r#"(?s)id="{}"[^>]*>(.*?)</(span|div|p)>"#, id))
+            .unwrap()
+            .captures(html)
+            .map(|c| tag_re.replace_all(&c[1], "").trim().to_string())
+            .filter(|msg| !msg.is_empty())
+    })
+}
+
+impl Cas {
+    fn need_captcha(&self, session: &Client, username: &str) -> anyhow::Result<bool> {
+        let response = session
+            .get(format!("{}/checkNeedCaptcha.htl", &self.url))
+            .query(&[("username", username)])
+            .send()?;
+        if !response.status().is_success() {
+            // older authservers do not have this endpoint
+            return Ok(false);
+        }
+        let result: Value = response.json().unwrap_or_default();
+        Ok(result
+            .get("isNeed")
+            .and_then(|v| v.as_bool())
+            .unwrap_or(false))
+    }
 }
 
 impl LoginProvider for Cas {
-    // TODO: remove this lint hint when implementing this
-    #[allow(unused_variables)]
     fn login(&self, session: &Client, username: &str, password: &str) -> anyhow::Result<String> {
-        todo!()
+        let login_url = format!("{}/login", &self.url);
+        let mut query: Vec<(&str, &str)> = vec![];
+        if let Some(service) = &self.service {
+            query.push(("service", service));
+        }
+
+        let login_page = session.get(&login_url).query(&query).send()?;
+        if !login_page.status().is_success() {
+            return Err(anyhow!(
+                "Unexpected response {} on login page",
+                login_page.status()
+            ));
+        }
+        let page_url = login_page.url().to_string();
+        let form = LoginForm::parse(&login_page.text()?)?;
+
+        let captcha = if self.need_captcha(session, username)? {
+            let image = session
+                .get(format!("{}/getCaptcha.htl", &self.url))
+                .send()?
+                .bytes()?;
+            self.captcha_solver.solve(&image)?
+        } else {
+            "".to_string()
+        };
+
+        let password = self
+            .password_encryption
+            .apply(password, form.encrypt_salt.as_deref())?;
+
+        let params = [
+            ("username", username),
+            ("password", &password),
+            ("captcha", &captcha),
+            ("rememberMe", "true"),
+            ("_eventId", &form.event_id),
+            ("cllt", "userNameLogin"),
+            ("dllt", "generalLogin"),
+            ("lt", &form.lt),
+            ("execution", &form.execution),
+            ("rmShown", "1"),
+        ];
+
+        let login_result = session
+            .post(&page_url)
+            .header("Referer", &page_url)
+            .form(&params)
+            .send()?;
+
+        let login_result_status = login_result.status();
+        if !login_result_status.is_redirection() {
+            let message = find_error_message(&login_result.text()?)
+                .unwrap_or_else(|| login_result_status.to_string());
+            return Err(LoginError::from_message(&message).into());
+        }
+
+        let redirect_url = login_result
+            .headers()
+            .get("Location")
+            .ok_or_else(|| LoginError::UnexpectedRedirect("".to_string()))?
+            .to_str()?
+            .to_owned();
+
+        let token = url::Url::parse(&redirect_url)?
+            .query_pairs()
+            .find(|(k, _)| k == "ticket")
+            .map(|(_, v)| v.into_owned())
+            .ok_or_else(|| LoginError::UnexpectedRedirect(redirect_url.clone()))?;
+
+        session.get(&redirect_url).send()?;
+        Ok(token)
     }
 
     fn get_type(&self) -> &'static str {
         "CAS"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use reqwest::blocking::Client;
+
+    use super::LoginProvider;
+    use super::{find_error_message, Cas, LoginForm};
+    use crate::cpdaily::loginprovider::captcha;
+
+    #[test]
+    fn test_login_form_parse() {
+        let html = r#"<form id="pwdFromId" method="post" action="/authserver/login?service=https%3A%2F%2Ftest.campusphere.net%2Fportal%2Flogin">
+            <input type="text" id="username" name="username" value="" />
+            <input type="hidden" id="captcha" name="captcha" value="" />
+            <input type="hidden" id="_eventId" name="_eventId" value="submit"/>
+            <input type="hidden" id="cllt" name="cllt" value="userNameLogin"/>
+            <input type="hidden" id="dllt" name="dllt" value="generalLogin"/>
+            <input type="hidden" id="lt" name="lt" value=""/>
+            <input type="hidden" name="execution" value="e1s1-abcdef"/>
+            <input type="hidden" id="pwdEncryptSalt" value="rjBFAaHsNkKAhpoi"/>
+        </form>
+        <form id="phoneFromId"><input type="hidden" name="execution" value="e1s1-other"/></form>"#;
+        let form = LoginForm::parse(html).unwrap();
+        assert_eq!(
+            form,
+            LoginForm {
+                lt: "".to_string(),
+                execution: "e1s1-abcdef".to_string(),
+                event_id: "submit".to_string(),
+                encrypt_salt: Some("rjBFAaHsNkKAhpoi".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn test_legacy_login_form_parse() {
+        let html = r#"<input type="hidden" name="lt" value="LT-12345-abcde-cas"/>
+            <input type="hidden" name="dllt" value="userNamePasswordLogin"/>
+            <input type="hidden" name="execution" value="e1s1"/>
+            <input type="hidden" name="_eventId" value="submit"/>
+            <input type="hidden" name="rmShown" value="1">
+            <script type="text/javascript">var pwdDefaultEncryptSalt = "Fs8XkrWzKE1oPMTs";</script>"#;
+        let form = LoginForm::parse(html).unwrap();
+        assert_eq!(form.lt, "LT-12345-abcde-cas");
+        assert_eq!(form.execution, "e1s1");
+        assert_eq!(form.encrypt_salt, Some("Fs8XkrWzKE1oPMTs".to_string()));
+        assert!(LoginForm::parse("<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_find_error_message() {
+        let html = r#"<span id="showErrorTip"><span>您提供的用户名或者密码有误</span></span>"#;
+        assert_eq!(
+            find_error_message(html),
+            Some("您提供的用户名或者密码有误".to_string())
+        );
+        let html = r#"<span id="msg" class="auth_error" style="top:-19px;">验证码错误</span>"#;
+        assert_eq!(find_error_message(html), Some("验证码错误".to_string()));
+        assert_eq!(find_error_message("<div></div>"), None);
+    }
+
+    #[test]
+    fn test_cas_login() {
+        let cas_url = env::var_os("CAS_URL");
+        let username = env::var_os("CAS_USERNAME");
+        let password = env::var_os("CAS_PASSWORD");
+        if cas_url.is_none() || username.is_none() || password.is_none() {
+            println!("CAS_URL, CAS_USERNAME, CAS_PASSWORD must be set. Skipping...");
+            return;
+        }
+
+        let client = Client::builder()
+            .cookie_store(true)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let cas = Cas {
+            url: cas_url.unwrap().to_str().unwrap().to_string(),
+            service: env::var("CAS_SERVICE").ok(),
+            captcha_solver: Box::new(captcha::Fail),
+            password_encryption: Default::default(),
+        };
+
+        let token = cas
+            .login(
+                &client,
+                username.unwrap().to_str().unwrap(),
+                password.unwrap().to_str().unwrap(),
+            )
+            .unwrap();
+
+        println!("{}", token);
+    }
+}
@@ -0,0 +1,188 @@
+use anyhow::anyhow;
+use serde_json::Value;
+use url::Url;
+
+use crate::cpdaily::client;
+use crate::cpdaily::structs::tenants::LoginProviderType;
+
+// path segments each login system is mounted under
+const KNOWN_SEGMENTS: [(&str, LoginProviderType); 3] = [
+    ("/iap", LoginProviderType::Iap),
+    ("/authserver", LoginProviderType::Cas),
+    ("/amp-auth-adapter", LoginProviderType::Rsa),
+];
+
+// cut `url` right after the first path segment we know, e.g.
+// https://host/authserver/login?service=... => https://host/authserver
+fn find_known_base(url: &Url) -> Option<(LoginProviderType, String)> {
+    let path = url.path();
+    KNOWN_SEGMENTS.iter().find_map(|(segment, login_type)| {
+        let start = path.find(segment)?;
+        let end = start + segment.len();
+        if path.len() > end && !path[end..].starts_with('/') {
+            return None;
+        }
+        Some((
+            *login_type,
+            format!("{}{}", url.origin().ascii_serialization(), &path[..end]),
+        ))
+    })
+}
+
+fn classify_login_page(
+    base: &str,
+    location: Option<&Url>,
+    body: &str,
+) -> Option<(LoginProviderType, String)> {
+    if let Some(found) = location.and_then(find_known_base) {
+        return Some(found);
+    }
+    let cas_markers = [
+        "pwdEncryptSalt",
+        "pwdDefaultEncryptSalt",
+        "name=\"execution\"",
+    ];
+    if cas_markers.iter().any(|marker| body.contains(marker)) {
+        return Some((LoginProviderType::Cas, base.to_owned()));
+    }
+    None
+}
+
+fn is_lt_response(result: &Value) -> bool {
+    result.get("result").and_then(|r| r.get("_lt")).is_some()
+}
+
+fn is_public_key_response(result: &Value) -> bool {
+    let result = result.get("data").unwrap_or(result);
+    result.get("publicKey").is_some() || result.get("modulus").is_some()
+}
+
+pub fn detect(ids_url: &str) -> anyhow::Result<(LoginProviderType, String)> {
+    let session = client::unauth()?;
+    let base = ids_url.trim_end_matches('/');
+    let mut tried = vec![];
+
+    if let Some(found) = Url::parse(base).ok().as_ref().and_then(find_known_base) {
+        return Ok(found);
+    }
+    tried.push(format!("known path segments in {}", base));
+
+    let url = format!("{}/login", base);
+    match session.get(&url).send() {
+        Ok(response) => {
+            let status = response.status();
+            let location = response
+                .headers()
+                .get("Location")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| response.url().join(v).ok());
+            let body = if status.is_success() {
+                response.text().unwrap_or_default()
+            } else {
+                "".to_string()
+            };
+            if let Some(found) = classify_login_page(base, location.as_ref(), &body) {
+                return Ok(found);
+            }
+            tried.push(format!("GET {} ({})", url, status));
+        }
+        Err(err) => tried.push(format!("GET {} ({})", url, err)),
+    }
+
+    let url = format!("{}/security/lt", base);
+    match session.post(&url).form(&[("lt", "")]).send() {
+        Ok(response) => {
+            if response.json::<Value>().is_ok_and(|v| is_lt_response(&v)) {
+                return Ok((LoginProviderType::Iap, base.to_owned()));
+            }
+            tried.push(format!("POST {}", url));
+        }
+        Err(err) => tried.push(format!("POST {} ({})", url, err)),
+    }
+
+    let url = format!("{}/getPublicKey", base);
+    match session.get(&url).send() {
+        Ok(response) => {
+            if response
+                .json::<Value>()
+                .is_ok_and(|v| is_public_key_response(&v))
+            {
+                return Ok((LoginProviderType::Rsa, base.to_owned()));
+            }
+            tried.push(format!("GET {}", url));
+        }
+        Err(err) => tried.push(format!("GET {} ({})", url, err)),
+    }
+
+    Err(anyhow!(
+        "Unable to detect the login provider of {}, tried: {}",
+        base,
+        tried.join(", ")
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use url::Url;
+
+    use super::{classify_login_page, find_known_base, is_lt_response, is_public_key_response};
+    use crate::cpdaily::structs::tenants::LoginProviderType;
+
+    #[test]
+    fn test_find_known_base() {
+        let url = Url::parse("https://ids.test.edu.cn/authserver/login?service=x").unwrap();
+        assert_eq!(
+            find_known_base(&url),
+            Some((
+                LoginProviderType::Cas,
+                "https://ids.test.edu.cn/authserver".to_string()
+            ))
+        );
+        let url = Url::parse("https://test.campusphere.net/iap/login").unwrap();
+        assert_eq!(
+            find_known_base(&url),
+            Some((
+                LoginProviderType::Iap,
+                "https://test.campusphere.net/iap".to_string()
+            ))
+        );
+        let url = Url::parse("https://test.edu.cn/iapx/login").unwrap();
+        assert_eq!(find_known_base(&url), None);
+    }
+
+    #[test]
+    fn test_classify_login_page() {
+        let base = "https://ids.test.edu.cn/sso";
+        let location = Url::parse("https://auth.test.edu.cn/amp-auth-adapter/loginPage").unwrap();
+        assert_eq!(
+            classify_login_page(base, Some(&location), ""),
+            Some((
+                LoginProviderType::Rsa,
+                "https://auth.test.edu.cn/amp-auth-adapter".to_string()
+            ))
+        );
+        assert_eq!(
+            classify_login_page(
+                base,
+                None,
+                r#"<input type="hidden" id="pwdEncryptSalt" value="abc"/>"#
+            ),
+            Some((LoginProviderType::Cas, base.to_string()))
+        );
+        assert_eq!(classify_login_page(base, None, "<html></html>"), None);
+    }
+
+    #[test]
+    fn test_probe_responses() {
+        assert!(is_lt_response(
+            &json!({"code": 200, "result": {"_lt": "abc", "_encryptSalt": ""}})
+        ));
+        assert!(!is_lt_response(&json!({"code": 404})));
+        assert!(is_public_key_response(&json!({"publicKey": "MFww"})));
+        assert!(is_public_key_response(
+            &json!({"data": {"modulus": "a8f7", "exponent": "010001"}})
+        ));
+        assert!(!is_public_key_response(&json!({"error": "not found"})));
+    }
+}
@@ -1,11 +1,20 @@
+use std::thread;
+
 use reqwest::blocking::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
 
-use super::LoginProvider;
+use super::{
+    captcha::CaptchaSolver, sms::SmsLogin, LoginError, LoginProvider, PasswordEncryption,
+    RetryPolicy,
+};
 
 pub struct Iap {
     pub url: String,
+    pub captcha_solver: Box<dyn CaptchaSolver>,
+    pub retry: RetryPolicy,
+    pub password_encryption: PasswordEncryption,
+    pub sms: Option<SmsLogin>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -39,11 +48,71 @@ pub struct LtResponse {
     pub need_captcha: bool,
 }
 
-impl LoginProvider for Iap {
-    fn login(&self, session: &Client, username: &str, password: &str) -> anyhow::Result<String> {
+fn parse_login_result(result: &Value) -> Result<String, LoginError> {
+    let result_code = result
+        .get("resultCode")
+        .and_then(|v| v.as_str())
+        .unwrap_or_default();
+    match result_code {
+        "REDIRECT" => result
+            .get("url")
+            .and_then(|v| v.as_str())
+            .map(|v| v.to_owned())
+            .ok_or_else(|| LoginError::UnexpectedRedirect("".to_string())),
+        "CAPTCHA_NOTMATCH" => Err(LoginError::CaptchaMismatch),
+        "FAIL_UPNOTMATCH" => Err(LoginError::WrongPassword),
+        "LT_NOTMATCH" => Err(LoginError::StaleLt),
+        "USER_FREEZE" | "USER_LOCKED" | "FAIL_LOCKED" => Err(LoginError::AccountLocked(
+            result
+                .get("msg")
+                .or_else(|| result.get("message"))
+                .and_then(|v| v.as_str())
+                .unwrap_or(result_code)
+                .to_owned(),
+        )),
+        _ => Err(LoginError::UnknownCode(result_code.to_owned())),
+    }
+}
+
+fn check_dynamic_code_result(result: &Value) -> anyhow::Result<()> {
+    let succeeded = result.get("code").and_then(|v| v.as_i64()) == Some(200)
+        || result.get("resultCode").and_then(|v| v.as_str()) == Some("SUCCESS");
+    if succeeded {
+        return Ok(());
+    }
+    let message = result
+        .get("message")
+        .or_else(|| result.get("msg"))
+        .and_then(|v| v.as_str())
+        .unwrap_or("Unknown error");
+    Err(anyhow::anyhow!("Failed to send SMS code: {}", message))
+}
+
+impl Iap {
+    fn send_dynamic_code(
+        &self,
+        session: &Client,
+        headers: &reqwest::header::HeaderMap,
+        mobile: &str,
+    ) -> anyhow::Result<()> {
+        let result: Value = session
+            .post(format!("{}/getDynamicCode", &self.url))
+            .headers(headers.clone())
+            .form(&[("mobile", mobile)])
+            .send()?
+            .json()?;
+        check_dynamic_code_result(&result)
+    }
+
+    fn try_login(
+        &self,
+        session: &Client,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<String> {
         let portal_url = self.url.clone().replace("/iap", "/portal/login");
         let anchor_response = session
-            .get(&format!("{}/login", &self.url))
+            .get(format!("{}/login", &self.url))
             .query(&[("service", &portal_url)])
             .send()?;
 
@@ -56,11 +125,12 @@ impl LoginProvider for Iap {
         let anchor_url = anchor_response
             .headers()
             .get("Location")
-            .unwrap()
-            .to_str()
-            .unwrap();
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| {
+                LoginError::UnexpectedRedirect("login page without a Location".to_string())
+            })?;
 
-        let prior_lt = &anchor_url[anchor_url.find('=').unwrap() + 1..anchor_url.len()];
+        let prior_lt = parse_anchor_lt(anchor_url)?;
 
         let mut headers = reqwest::header::HeaderMap::new();
         headers.append(
@@ -77,59 +147,107 @@ impl LoginProvider for Iap {
         );
 
         let lt_info: IapResponse<LtResponse> = session
-            .post(&format!("{}/security/lt", &self.url))
+            .post(format!("{}/security/lt", &self.url))
             .headers(headers.clone())
             .form(&[("lt", prior_lt)])
             .send()?
             .json()?;
-        let params = LoginParams {
+        let mut params = LoginParams {
             lt: lt_info.result.lt,
             remember_me: false,
             dllt: "".to_string(),
             mobile: "".to_string(),
             username: username.to_string(),
-            password: password.to_string(),
+            password: "".to_string(),
             captcha: "".to_string(),
         };
 
-        let need_captcha = {
-            let result: Value = session
-                .post(&format!("{}/checkNeedCaptcha", &self.url))
-                .headers(headers.clone())
-                .query(&[("username", username)])
-                .json(&json!({}))
-                .send()?
-                .json()?;
-            result.get("needCaptcha").unwrap().as_bool().unwrap()
-        };
+        if let Some(sms) = &self.sms {
+            // dynamic code login: the code goes into dllt instead of a password
+            self.send_dynamic_code(session, &headers, &sms.mobile)?;
+            params.mobile = sms.mobile.clone();
+            params.dllt = sms.code_reader.read(&sms.mobile)?;
+        } else {
+            params.password = self
+                .password_encryption
+                .apply(password, Some(&lt_info.result.encrypt_salt))?;
 
-        if need_captcha {
-            todo!();
+            let need_captcha = {
+                let result: Value = session
+                    .post(format!("{}/checkNeedCaptcha", &self.url))
+                    .headers(headers.clone())
+                    .query(&[("username", username)])
+                    .json(&json!({}))
+                    .send()?
+                    .json()?;
+                parse_need_captcha(&result)?
+            };
+
+            if need_captcha {
+                let image = session
+                    .get(format!("{}/generateCaptcha", &self.url))
+                    .query(&[("ltId", &params.lt)])
+                    .send()?
+                    .bytes()?;
+                params.captcha = self.captcha_solver.solve(&image)?;
+            }
         }
 
         let login_result = session
-            .post(&format!("{}/doLogin", &self.url))
+            .post(format!("{}/doLogin", &self.url))
             .headers(headers.clone())
             .form(&params)
             .send()?;
 
         let result_obj: Value = login_result.json()?;
-        let result_code = result_obj.get("resultCode").unwrap().as_str().unwrap();
-        match result_code {
-            "REDIRECT" => {
-                let redirect_url = result_obj.get("url").unwrap().as_str().unwrap();
+        let redirect_url = parse_login_result(&result_obj)?;
+        let token = match redirect_url.find('=') {
+            Some(i) if i + 1 < redirect_url.len() => redirect_url[i + 1..].to_owned(),
+            _ => return Err(LoginError::UnexpectedRedirect(redirect_url).into()),
+        };
+
+        session.get(&redirect_url).send()?;
+        Ok(token)
+    }
+}
 
-                let token = &redirect_url[redirect_url.find('=').unwrap() + 1..redirect_url.len()];
+// the anchor redirects to a url ending in `=<lt>`
+fn parse_anchor_lt(anchor_url: &str) -> Result<&str, LoginError> {
+    anchor_url
+        .find('=')
+        .map(|i| &anchor_url[i + 1..])
+        .ok_or_else(|| LoginError::UnexpectedRedirect(anchor_url.to_owned()))
+}
 
-                assert_ne!(token, "", "Token is empty");
+fn parse_need_captcha(result: &Value) -> Result<bool, LoginError> {
+    result
+        .get("needCaptcha")
+        .and_then(Value::as_bool)
+        .ok_or_else(|| LoginError::UnknownCode(result.to_string()))
+}
 
-                session.get(redirect_url).send()?;
-                Ok(token.to_owned())
+impl LoginProvider for Iap {
+    fn login(&self, session: &Client, username: &str, password: &str) -> anyhow::Result<String> {
+        let mut attempt = 1;
+        loop {
+            match self.try_login(session, username, password) {
+                // the lt went stale between /security/lt and /doLogin,
+                // start over with a fresh anchor
+                Err(err)
+                    if attempt < self.retry.attempts
+                        && err.downcast_ref::<LoginError>() == Some(&LoginError::StaleLt) =>
+                {
+                    crate::logger::log(sentry::Breadcrumb {
+                        category: Some("login".to_string()),
+                        message: Some(format!("lt went stale, retry {}", attempt)),
+                        level: sentry::Level::Warning,
+                        ..Default::default()
+                    });
+                    thread::sleep(self.retry.backoff(attempt));
+                    attempt += 1;
+                }
+                result => return result,
             }
-            "CAPTCHA_NOTMATCH" => Err(anyhow::anyhow!("CAPTCHA_NOTMATCH")),
-            "FAIL_UPNOTMATCH" => Err(anyhow::anyhow!("FAIL_UPNOTMATCH")),
-            "LT_NOTMATCH" => panic!("LT_NOTMATCH"),
-            _ => unimplemented!(),
         }
     }
 
@@ -144,8 +262,14 @@ mod tests {
 
     use reqwest::blocking::Client;
 
-    use super::LoginProvider;
-    use super::{Iap, IapResponse, LtResponse};
+    use serde_json::json;
+
+    use super::{
+        check_dynamic_code_result, parse_anchor_lt, parse_login_result, parse_need_captcha, Iap,
+        IapResponse, LtResponse,
+    };
+    use super::{LoginError, LoginProvider};
+    use crate::cpdaily::loginprovider::captcha;
 
     #[test]
     fn test_lt_deserialise() {
@@ -162,7 +286,46 @@ mod tests {
             parsed_response.result.forget_pwd_url,
             "/personCenter/new_password_retrieve/index.html"
         );
-        assert_eq!(parsed_response.result.need_captcha, false);
+        assert!(!parsed_response.result.need_captcha);
+    }
+
+    #[test]
+    fn test_parse_login_result() {
+        assert_eq!(
+            parse_login_result(
+                &json!({"resultCode": "REDIRECT", "url": "https://test.campusphere.net/portal/login?ticket=ST-1234"})
+            ),
+            Ok("https://test.campusphere.net/portal/login?ticket=ST-1234".to_string())
+        );
+        assert_eq!(
+            parse_login_result(&json!({"resultCode": "FAIL_UPNOTMATCH"})),
+            Err(LoginError::WrongPassword)
+        );
+        assert_eq!(
+            parse_login_result(&json!({"resultCode": "CAPTCHA_NOTMATCH"})),
+            Err(LoginError::CaptchaMismatch)
+        );
+        assert_eq!(
+            parse_login_result(&json!({"resultCode": "LT_NOTMATCH"})),
+            Err(LoginError::StaleLt)
+        );
+        assert_eq!(
+            parse_login_result(&json!({"resultCode": "USER_FREEZE", "msg": "账号已冻结"})),
+            Err(LoginError::AccountLocked("账号已冻结".to_string()))
+        );
+        assert_eq!(
+            parse_login_result(&json!({"resultCode": "SOMETHING_NEW"})),
+            Err(LoginError::UnknownCode("SOMETHING_NEW".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_check_dynamic_code_result() {
+        assert!(check_dynamic_code_result(&json!({"code": 200, "message": "操作成功"})).is_ok());
+        assert!(check_dynamic_code_result(&json!({"resultCode": "SUCCESS"})).is_ok());
+        let err = check_dynamic_code_result(&json!({"code": 500, "message": "发送过于频繁"}))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Failed to send SMS code: 发送过于频繁");
     }
 
     #[test]
@@ -182,16 +345,38 @@ mod tests {
             .unwrap();
         let iap = Iap {
             url: iap_url.unwrap().to_str().unwrap().to_string(),
+            captcha_solver: Box::new(captcha::Fail),
+            retry: Default::default(),
+            password_encryption: Default::default(),
+            sms: None,
         };
 
         let token = iap
             .login(
                 &client,
-                &username.unwrap().to_str().unwrap().to_string(),
-                &password.unwrap().to_str().unwrap().to_string(),
+                username.unwrap().to_str().unwrap(),
+                password.unwrap().to_str().unwrap(),
             )
             .unwrap();
 
         println!("{}", token);
     }
+
+    #[test]
+    fn test_parse_anchor() {
+        assert_eq!(
+            parse_anchor_lt("https://example.com/iap/login?lt=abc").unwrap(),
+            "abc"
+        );
+        assert_eq!(
+            parse_anchor_lt("https://example.com/portal").unwrap_err(),
+            LoginError::UnexpectedRedirect("https://example.com/portal".to_string())
+        );
+
+        assert!(parse_need_captcha(&json!({"needCaptcha": true})).unwrap());
+        assert_eq!(
+            parse_need_captcha(&json!({"code": 500})).unwrap_err(),
+            LoginError::UnknownCode(r#"{"code":500}"#.to_string())
+        );
+    }
 }
@@ -1,19 +1,212 @@
+use anyhow::anyhow;
 use reqwest::blocking::Client;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use url::Url;
 
-use super::LoginProvider;
+use super::{LoginError, LoginProvider};
+use crate::cpdaily::crypto::ciphers::{base64, rsa};
+
+const MAX_REDIRECTS: usize = 10;
 
 pub struct Rsa {
     pub url: String,
+    pub service: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+struct PublicKeyResponse {
+    pub public_key: Option<String>,
+    pub modulus: Option<String>,
+    pub exponent: Option<String>,
+}
+
+impl PublicKeyResponse {
+    fn to_pem(&self) -> anyhow::Result<String> {
+        match (&self.public_key, &self.modulus, &self.exponent) {
+            (Some(key), _, _) if key.contains("-----BEGIN") => Ok(key.to_owned()),
+            (Some(key), _, _) => Ok(rsa::public_key_from_base64(key)?),
+            (None, Some(modulus), Some(exponent)) => {
+                Ok(rsa::public_key_from_components(modulus, exponent)?)
+            }
+            _ => Err(anyhow!("No public key in adapter response")),
+        }
+    }
+}
+
+fn get_location(response: &reqwest::blocking::Response) -> anyhow::Result<Url> {
+    let location = response
+        .headers()
+        .get("Location")
+        .ok_or_else(|| anyhow!("Redirect without location"))?
+        .to_str()?;
+    // relative redirects are common on the adapter
+    Ok(response.url().join(location)?)
+}
+
+fn get_ticket(url: &Url) -> Option<String> {
+    url.query_pairs()
+        .find(|(k, _)| k == "ticket")
+        .map(|(_, v)| v.into_owned())
+}
+
+impl Rsa {
+    fn get_public_key(&self, session: &Client) -> anyhow::Result<String> {
+        let result: Value = session
+            .get(format!("{}/getPublicKey", &self.url))
+            .send()?
+            .json()?;
+        // some deployments wrap the key in a "data" envelope
+        let result = result.get("data").cloned().unwrap_or(result);
+        let response: PublicKeyResponse = serde_json::from_value(result)?;
+        response.to_pem()
+    }
+
+    fn encrypt(&self, text: &str, key: &str) -> anyhow::Result<String> {
+        Ok(base64::encode(&rsa::public_encrypt(text, Some(key))?).replace(' ', ""))
+    }
 }
 
 impl LoginProvider for Rsa {
-    // TODO: remove this lint hint when implementing this
-    #[allow(unused_variables)]
     fn login(&self, session: &Client, username: &str, password: &str) -> anyhow::Result<String> {
-        todo!()
+        let mut query: Vec<(&str, &str)> = vec![];
+        if let Some(service) = &self.service {
+            query.push(("service", service));
+        }
+
+        // land on the adapter login page so that its session cookie is set
+        let mut response = session
+            .get(format!("{}/login", &self.url))
+            .query(&query)
+            .send()?;
+        for _ in 0..MAX_REDIRECTS {
+            if !response.status().is_redirection() {
+                break;
+            }
+            response = session.get(get_location(&response)?).send()?;
+        }
+        if !response.status().is_success() {
+            return Err(anyhow!(
+                "Unexpected response {} on login page",
+                response.status()
+            ));
+        }
+
+        let public_key = self.get_public_key(session)?;
+        let mut params = vec![
+            ("username", self.encrypt(username, &public_key)?),
+            ("password", self.encrypt(password, &public_key)?),
+        ];
+        if let Some(service) = &self.service {
+            params.push(("service", service.to_owned()));
+        }
+
+        let login_result = session
+            .post(format!("{}/login", &self.url))
+            .header("Referer", response.url().as_str())
+            .form(&params)
+            .send()?;
+
+        if !login_result.status().is_redirection() {
+            let body = login_result.text()?;
+            let message = serde_json::from_str::<Value>(&body)
+                .ok()
+                .and_then(|v| {
+                    v.get("message")
+                        .or_else(|| v.get("msg"))
+                        .and_then(|m| m.as_str().map(|m| m.to_owned()))
+                })
+                .unwrap_or_else(|| "Unknown error".to_string());
+            return Err(LoginError::from_message(&message).into());
+        }
+
+        let redirect_url = get_location(&login_result)?;
+        let token = get_ticket(&redirect_url)
+            .ok_or_else(|| LoginError::UnexpectedRedirect(redirect_url.to_string()))?;
+
+        // walk the redirect chain until the campus sets MOD_AUTH_CAS
+        let mut response = session.get(redirect_url).send()?;
+        for _ in 0..MAX_REDIRECTS {
+            if !response.status().is_redirection() {
+                break;
+            }
+            response = session.get(get_location(&response)?).send()?;
+        }
+
+        Ok(token)
     }
 
     fn get_type(&self) -> &'static str {
         "RSA"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use reqwest::blocking::Client;
+    use url::Url;
+
+    use super::LoginProvider;
+    use super::{get_ticket, PublicKeyResponse, Rsa};
+
+    #[test]
+    fn test_public_key_deserialise() {
+        let response = r#"{"publicKey":"MFwwDQYJKoZIhvcNAQEBBQADSwAwSAJBALFoKl5TnwSuLInNHvqSFbBhihoa+t3PIsCHxegNrJFj3JxzC15kaS6RyKjqVyhh5+CGXWtanOQour52fX1sHc8CAwEAAQ=="}"#;
+        let parsed_response: PublicKeyResponse = serde_json::from_str(response).unwrap();
+        assert!(parsed_response
+            .to_pem()
+            .unwrap()
+            .starts_with("-----BEGIN PUBLIC KEY-----"));
+
+        let response = r#"{"modulus":"a8f7e0693116","exponent":"010001"}"#;
+        let parsed_response: PublicKeyResponse = serde_json::from_str(response).unwrap();
+        assert_eq!(parsed_response.modulus, Some("a8f7e0693116".to_string()));
+        assert_eq!(parsed_response.exponent, Some("010001".to_string()));
+
+        let parsed_response: PublicKeyResponse = serde_json::from_str("{}").unwrap();
+        assert!(parsed_response.to_pem().is_err());
+    }
+
+    #[test]
+    fn test_get_ticket() {
+        let url =
+            Url::parse("https://test.campusphere.net/portal/login?ticket=ST-1234-abcd").unwrap();
+        assert_eq!(get_ticket(&url), Some("ST-1234-abcd".to_string()));
+        let url = Url::parse("https://test.campusphere.net/portal/login").unwrap();
+        assert_eq!(get_ticket(&url), None);
+    }
+
+    #[test]
+    fn test_rsa_login() {
+        let rsa_url = env::var_os("RSA_URL");
+        let username = env::var_os("RSA_USERNAME");
+        let password = env::var_os("RSA_PASSWORD");
+        if rsa_url.is_none() || username.is_none() || password.is_none() {
+            println!("RSA_URL, RSA_USERNAME, RSA_PASSWORD must be set. Skipping...");
+            return;
+        }
+
+        let client = Client::builder()
+            .cookie_store(true)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .unwrap();
+        let rsa = Rsa {
+            url: rsa_url.unwrap().to_str().unwrap().to_string(),
+            service: env::var("RSA_SERVICE").ok(),
+        };
+
+        let token = rsa
+            .login(
+                &client,
+                username.unwrap().to_str().unwrap(),
+                password.unwrap().to_str().unwrap(),
+            )
+            .unwrap();
+
+        println!("{}", token);
+    }
+}
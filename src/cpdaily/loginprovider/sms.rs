@@ -0,0 +1,76 @@
+use std::io::{self, Write};
+
+use anyhow::anyhow;
+
+use super::{lock_prompt, run_hook};
+
+pub trait CodeReader {
+    fn get_type(&self) -> &'static str;
+    fn read(&self, mobile: &str) -> anyhow::Result<String>;
+}
+
+pub struct SmsLogin {
+    pub mobile: String,
+    pub code_reader: Box<dyn CodeReader>,
+}
+
+// asks for the code on the terminal
+pub struct Prompt;
+
+impl CodeReader for Prompt {
+    fn get_type(&self) -> &'static str {
+        "Prompt"
+    }
+
+    fn read(&self, mobile: &str) -> anyhow::Result<String> {
+        let _prompt = lock_prompt();
+        print!("Code sent to {}, please enter it: ", mobile);
+        io::stdout().flush()?;
+
+        let mut code = String::new();
+        io::stdin().read_line(&mut code)?;
+        let code = code.trim();
+        if code.is_empty() {
+            return Err(anyhow!("No SMS code entered"));
+        }
+        Ok(code.to_owned())
+    }
+}
+
+// runs an external command with MOBILE set and reads the code from its stdout
+pub struct Command {
+    pub command: String,
+}
+
+impl CodeReader for Command {
+    fn get_type(&self) -> &'static str {
+        "Command"
+    }
+
+    fn read(&self, mobile: &str) -> anyhow::Result<String> {
+        let code = run_hook(&self.command, b"", &[("MOBILE", mobile)])?;
+        if code.is_empty() {
+            return Err(anyhow!("SMS code command printed nothing"));
+        }
+        Ok(code)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CodeReader, Command};
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_reader() {
+        let reader = Command {
+            command: "echo 1234-$MOBILE".to_string(),
+        };
+        assert_eq!(reader.read("13800000000").unwrap(), "1234-13800000000");
+
+        let reader = Command {
+            command: "true".to_string(),
+        };
+        assert!(reader.read("13800000000").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+// async counterparts of the blocking calls, for use inside a tokio runtime;
+// see also client::new_async, first_v2::Local::fetch and
+// actions::counselor_form_fill::nonblocking.
+//
+// This is a stopgap until the login providers are async: the blocking calls
+// stay the primary API and the ones here mirror them, so a change to one side
+// has to be made to the other.
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use url::Url;
+
+use super::session::CookieJar;
+use super::structs::tenants::{parse_tenant_info, Tenant, TenantDetail, TENANT_INFO_URL};
+use super::{client, parse_tenant_list, TENANT_LIST_URL};
+use crate::actions::counselor_form_fill::nonblocking::is_session_valid;
+use crate::config::User;
+use crate::logger;
+
+pub async fn get_all_tenants() -> Result<Vec<Tenant>> {
+    let response: Value = client::unauth_async()?
+        .get(TENANT_LIST_URL)
+        .send()
+        .await?
+        .json()
+        .await?;
+    parse_tenant_list(response)
+}
+
+pub async fn get_tenant_info(tenant: &Tenant) -> Result<TenantDetail> {
+    let result: Value = client::unauth_async()?
+        .get(TENANT_INFO_URL)
+        .query(&[("ids", &tenant.id)])
+        .send()
+        .await?
+        .json()
+        .await?;
+    parse_tenant_info(result)
+}
+
+// the async side of commands::open_session: an imported MOD_AUTH_CAS, or a
+// still valid session in `cookie_jar` when `reuse` is set, is used as is and
+// only otherwise does the user log in. The login providers block, on captcha
+// and sms hooks too, so they run on the blocking pool; the session lands in
+// `cookie_jar`, where a client from client::new_async picks it up
+pub async fn login(
+    user: User,
+    tenant: Tenant,
+    cookie_jar: Arc<CookieJar>,
+    reuse: bool,
+) -> Result<()> {
+    let base_url = get_tenant_info(&tenant).await?.get_url()?;
+    let client = client::new_async(&user, cookie_jar.clone())?;
+
+    if let Some(mod_auth_cas) = &user.mod_auth_cas {
+        // the cookie was exported from the app, never fall back to logging in
+        cookie_jar.import_mod_auth_cas(mod_auth_cas, &Url::parse(&base_url)?);
+
+        // a network failure says nothing about the cookie
+        let valid = is_session_valid(&client, &base_url)
+            .await
+            .context("Failed to check the imported MOD_AUTH_CAS")?;
+        if !valid {
+            bail!(
+                "Imported MOD_AUTH_CAS of {}:{} has expired, please export a new one",
+                &user.school,
+                &user.username
+            );
+        }
+
+        logger::log(sentry::Breadcrumb {
+            category: Some("login".to_string()),
+            message: Some("use imported MOD_AUTH_CAS".to_string()),
+            level: sentry::Level::Info,
+            ..Default::default()
+        });
+        return Ok(());
+    }
+
+    if reuse
+        && !cookie_jar.is_empty()
+        && is_session_valid(&client, &base_url).await.unwrap_or(false)
+    {
+        logger::log(sentry::Breadcrumb {
+            category: Some("login".to_string()),
+            message: Some("reuse saved session".to_string()),
+            level: sentry::Level::Info,
+            ..Default::default()
+        });
+        return Ok(());
+    }
+
+    cookie_jar.clear();
+
+    tokio::task::spawn_blocking(move || {
+        let client = client::new(&user, cookie_jar)?;
+        let password = user.password.resolve()?;
+        let options = user.get_login_options();
+        let login_provider = match &user.login {
+            Some(login) => login.create_login(options)?,
+            None => tenant.create_login(options)?,
+        };
+
+        login_provider
+            .login(&client, &user.username, &password)
+            .map(|_| ())
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    #[tokio::test]
+    async fn test_get_all_tenants() {
+        if env::var_os("CPDAILY_NETWORK_TESTS").is_none() {
+            println!("CPDAILY_NETWORK_TESTS is not set. Skipping...");
+            return;
+        }
+        let tenants = super::get_all_tenants().await.unwrap();
+        let tenant = tenants.first().unwrap();
+        super::get_tenant_info(tenant).await.unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use anyhow::anyhow;
+use reqwest::header::HeaderValue;
+use url::Url;
+
+// A cookie jar for reqwest that, unlike `reqwest::cookie::Jar`, can be written
+// to disk including session cookies such as MOD_AUTH_CAS.
+#[derive(Default)]
+pub struct CookieJar(RwLock<cookie_store::CookieStore>);
+
+impl CookieJar {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let store = cookie_store::CookieStore::load_json(reader).map_err(|e| anyhow!(e))?;
+        Ok(CookieJar(RwLock::new(store)))
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut contents = vec![];
+        // CookieStore::save_json drops non-persistent cookies, which are the
+        // ones we care about, so write them out one per line ourselves
+        for cookie in self.0.read().unwrap().iter_unexpired() {
+            writeln!(contents, "{}", serde_json::to_string(cookie)?)?;
+        }
+        // the cookies log the user in, keep them from other local users
+        write_atomic(path, &contents, 0o600)
+    }
+
+    pub fn add_cookie_str(&self, cookie: &str, url: &Url) {
+        let cookies = cookie::Cookie::parse(cookie)
+            .ok()
+            .map(|c| c.into_owned())
+            .into_iter();
+        self.0.write().unwrap().store_response_cookies(cookies, url);
+    }
+
+    // replaces the session with a MOD_AUTH_CAS exported from the app, given
+    // with or without the "MOD_AUTH_CAS=" prefix
+    pub fn import_mod_auth_cas(&self, value: &str, url: &Url) {
+        self.clear();
+        self.add_cookie_str(
+            &format!(
+                "MOD_AUTH_CAS={}",
+                value.trim().trim_start_matches("MOD_AUTH_CAS=")
+            ),
+            url,
+        );
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.read().unwrap().iter_unexpired().next().is_none()
+    }
+
+    pub fn clear(&self) {
+        self.0.write().unwrap().clear();
+    }
+}
+
+impl reqwest::cookie::CookieStore for CookieJar {
+    fn set_cookies(&self, cookie_headers: &mut dyn Iterator<Item = &HeaderValue>, url: &Url) {
+        let cookies = cookie_headers.filter_map(|value| {
+            let value = value.to_str().ok()?;
+            cookie::Cookie::parse(value).ok().map(|c| c.into_owned())
+        });
+        self.0.write().unwrap().store_response_cookies(cookies, url);
+    }
+
+    fn cookies(&self, url: &Url) -> Option<HeaderValue> {
+        let value = self
+            .0
+            .read()
+            .unwrap()
+            .get_request_values(url)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<_>>()
+            .join("; ");
+        if value.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&value).ok()
+    }
+}
+
+// write to a temp file next to `path` and rename it over, so a crash never
+// leaves a truncated file behind; `mode` only applies on unix
+pub fn write_atomic(path: &Path, contents: &[u8], mode: u32) -> anyhow::Result<()> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| anyhow!("{} is not a file", path.display()))?;
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name.to_string_lossy(),
+        uuid::Uuid::new_v4()
+    ));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    #[cfg(not(unix))]
+    let _ = mode;
+
+    let result = (|| -> std::io::Result<()> {
+        let mut file = options.open(&temp_path)?;
+        // the umask may have taken bits away from `mode`
+        #[cfg(unix)]
+        file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(mode))?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    Ok(result?)
+}
+
+// the mode of an existing file, so rewriting it keeps who may read it, or
+// `default` for a new one
+pub fn file_mode(path: &Path, default: u32) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        if let Ok(metadata) = fs::metadata(path) {
+            return metadata.permissions().mode() & 0o777;
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    default
+}
+
+// unique per user and safe to use in a file name
+pub fn get_user_file_stem(school: &str, username: &str) -> String {
+    let sanitize = |s: &str| -> String {
+        s.chars()
+            .map(|c| if c.is_alphanumeric() { c } else { '_' })
+            .collect()
+    };
+    format!("{}-{}", sanitize(school), sanitize(username))
+}
+
+pub fn get_session_path(dir: &str, school: &str, username: &str) -> PathBuf {
+    Path::new(dir).join(format!("{}.json", get_user_file_stem(school, username)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use reqwest::cookie::CookieStore;
+    use reqwest::header::HeaderValue;
+    use url::Url;
+
+    use super::{get_session_path, CookieJar};
+
+    #[test]
+    fn test_cookie_jar_roundtrip() {
+        let url = Url::parse("https://test.campusphere.net/portal/login").unwrap();
+        let jar = CookieJar::default();
+        assert!(jar.is_empty());
+        jar.set_cookies(
+            &mut [
+                HeaderValue::from_static("MOD_AUTH_CAS=ST-1234-abcd; Path=/"),
+                HeaderValue::from_static("acw_tc=xyz; Path=/; Max-Age=1800"),
+            ]
+            .iter(),
+            &url,
+        );
+        assert!(!jar.is_empty());
+
+        let path = env::temp_dir().join(format!("cookie-jar-{}.json", uuid::Uuid::new_v4()));
+        jar.save(&path).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        // saving again replaces the file
+        jar.save(&path).unwrap();
+        let loaded = CookieJar::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let cookies = loaded.cookies(&url).unwrap();
+        let cookies = cookies.to_str().unwrap();
+        assert!(cookies.contains("MOD_AUTH_CAS=ST-1234-abcd"));
+        assert!(cookies.contains("acw_tc=xyz"));
+
+        loaded.clear();
+        assert!(loaded.is_empty());
+        assert_eq!(loaded.cookies(&url), None);
+    }
+
+    #[test]
+    fn test_add_cookie_str() {
+        let url = Url::parse("https://test.campusphere.net").unwrap();
+        let jar = CookieJar::default();
+        jar.add_cookie_str("MOD_AUTH_CAS=ST-1234-abcd", &url);
+        let cookies = jar
+            .cookies(&Url::parse("https://test.campusphere.net/wec-counselor-collector-apps/stu/collector/queryCollectorProcessingList").unwrap())
+            .unwrap();
+        assert_eq!(cookies.to_str().unwrap(), "MOD_AUTH_CAS=ST-1234-abcd");
+    }
+
+    #[test]
+    fn test_import_mod_auth_cas() {
+        let url = Url::parse("https://test.campusphere.net").unwrap();
+        let jar = CookieJar::default();
+        jar.add_cookie_str("acw_tc=old", &url);
+        jar.import_mod_auth_cas(" MOD_AUTH_CAS=ST-1234-abcd\n", &url);
+        let cookies = jar.cookies(&url).unwrap();
+        assert_eq!(cookies.to_str().unwrap(), "MOD_AUTH_CAS=ST-1234-abcd");
+    }
+
+    #[test]
+    fn test_get_session_path() {
+        assert_eq!(
+            get_session_path("sessions", "10000", "user/name"),
+            std::path::Path::new("sessions").join("10000-user_name.json")
+        );
+    }
+}
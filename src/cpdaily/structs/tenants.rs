@@ -1,9 +1,10 @@
 use crate::cpdaily::client;
-use crate::cpdaily::loginprovider::{cas, iap, rsa, LoginProvider};
+use crate::cpdaily::loginprovider::{self, detect, LoginOptions, LoginProvider};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use url::{ParseError, Url};
 
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum LoginProviderType {
     Unknown,
     Iap,
@@ -11,7 +12,18 @@ pub enum LoginProviderType {
     Cas,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+pub(crate) const TENANT_INFO_URL: &str = "https://mobile.campushoy.com/v6/config/guest/tenant/info";
+
+pub(crate) fn parse_tenant_info(result: Value) -> anyhow::Result<TenantDetail> {
+    let detail = result
+        .get("data")
+        .ok_or_else(|| anyhow::anyhow!("Data not found"))?
+        .get(0)
+        .ok_or_else(|| anyhow::anyhow!("School not found"))?;
+    Ok(serde_json::from_value(detail.to_owned())?)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 pub struct Tenant {
     pub id: String,
@@ -118,18 +130,11 @@ pub struct TenantDetail {
 impl Tenant {
     pub fn get_info(&self) -> anyhow::Result<TenantDetail> {
         let result: Value = client::unauth()?
-            .get("https://mobile.campushoy.com/v6/config/guest/tenant/info")
+            .get(TENANT_INFO_URL)
             .query(&[("ids", &self.id)])
             .send()?
             .json()?;
-        Ok(serde_json::from_value(
-            result
-                .get("data")
-                .expect("Data not found")
-                .get(0)
-                .expect("School not found")
-                .to_owned(),
-        )?)
+        parse_tenant_info(result)
     }
 
     pub fn get_login_type(&self) -> LoginProviderType {
@@ -148,19 +153,20 @@ impl Tenant {
         }
     }
 
-    pub fn create_login(&self) -> Box<dyn LoginProvider> {
-        match self.get_login_type() {
-            LoginProviderType::Iap => Box::new(iap::Iap {
-                url: self.ids_url.to_owned(),
-            }),
-            LoginProviderType::Cas => Box::new(cas::Cas {
-                url: self.ids_url.to_owned(),
-            }),
-            LoginProviderType::Rsa => Box::new(rsa::Rsa {
-                url: self.ids_url.to_owned(),
-            }),
-            _ => unimplemented!(),
-        }
+    fn get_cas_service(&self) -> Option<String> {
+        Url::parse(&self.cas_login_url)
+            .ok()?
+            .query_pairs()
+            .find(|(k, _)| k == "service")
+            .map(|(_, v)| v.into_owned())
+    }
+
+    pub fn create_login(&self, options: LoginOptions) -> anyhow::Result<Box<dyn LoginProvider>> {
+        let (login_type, url) = match self.get_login_type() {
+            LoginProviderType::Unknown => detect::detect(&self.ids_url)?,
+            login_type => (login_type, self.ids_url.to_owned()),
+        };
+        loginprovider::create(login_type, url, self.get_cas_service(), options)
     }
 }
 
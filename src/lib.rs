@@ -0,0 +1,4 @@
+pub mod actions;
+pub mod config;
+pub mod cpdaily;
+pub mod logger;
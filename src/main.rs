@@ -1,15 +1,10 @@
-mod actions;
-mod config;
-mod cpdaily;
-mod logger;
+mod commands;
+mod wizard;
 
-use cpdaily::client;
-use getopts::{Matches, Options};
-use serde_json::json;
-use std::{collections::BTreeMap, env, str};
+use i_am_alive_today::{actions, config, cpdaily, logger};
 
-use config::Action;
-use cpdaily::crypto::providers::first_v2;
+use getopts::{Matches, Options};
+use std::env;
 
 fn main() {
     #[cfg(feature = "telemetry")]
@@ -42,109 +37,41 @@ fn main() {
     let config_file_path = matches
         .opt_str("c")
         .unwrap_or_else(|| default_config_path.to_str().unwrap().to_string());
-    let config = config::load_config(&config_file_path).expect("Config file not found");
-
-    logger::log(sentry::Breadcrumb {
-        category: Some("config".to_string()),
-        message: Some("loaded config file".to_string()),
-        data: {
-            let mut bt = BTreeMap::new();
-            for u in &config.users {
-                bt.insert(
-                    format!("user-{}-{}", &u.school, &u.username),
-                    json!({
-                        "school": u.school,
-                        "username": u.username,
-                        "address": u.address,
-                        "device_info": u.device_info,
-                        "actions": u.actions,
-                    }),
-                );
-            }
-            bt
-        },
-        level: sentry::Level::Info,
-        ..Default::default()
-    });
-
-    // Initialize crypto providers
-    let first_v2_provider = first_v2::Local::new();
-
-    // Fetch tenant list
-    let tenant_list = cpdaily::get_all_tenants().unwrap();
-
-    // For each user
-    for user in &config.users {
-        logger::log(sentry::Breadcrumb {
-            category: Some("bus".to_string()),
-            message: Some(format!("start user {}:{}", &user.school, &user.username)),
-            level: sentry::Level::Info,
-            ..Default::default()
-        });
-
-        let client = client::new(user).unwrap();
-        let tenant = cpdaily::match_school_from_tenant_list(&tenant_list, &user.school).unwrap();
-
-        logger::log(sentry::Breadcrumb {
-            category: Some("tenant_service".to_string()),
-            message: Some(format!(
-                "matched \"{}\" to tenant \"{}\"",
-                &user.school, &tenant.name
-            )),
-            level: sentry::Level::Info,
-            ..Default::default()
-        });
-
-        let login_provider = tenant.create_login();
-
-        logger::log(sentry::Breadcrumb {
-            category: Some("login".to_string()),
-            message: Some(format!("use login provider {}", login_provider.get_type())),
-            level: sentry::Level::Info,
-            ..Default::default()
-        });
 
-        login_provider
-            .login(&client, &user.username, &user.password)
-            .unwrap();
-
-        logger::log(sentry::Breadcrumb {
-            category: Some("login".to_string()),
-            message: Some("logged in".to_string()),
-            level: sentry::Level::Info,
-            ..Default::default()
-        });
-
-        let tenant_detail = tenant.get_info().unwrap();
-
-        let base_url = tenant_detail.get_url().unwrap();
-
-        logger::log(sentry::Breadcrumb {
-            category: Some("bus".to_string()),
-            message: Some(format!("set base url to \"{}\"", &base_url)),
-            level: sentry::Level::Info,
-            ..Default::default()
-        });
-
-        for action in &user.actions {
-            match action {
-                Action::CounselorFormFill(form_fill) => actions::counselor_form_fill::perform(
-                    &client,
-                    &base_url,
-                    form_fill,
-                    user,
-                    &first_v2_provider,
-                )
-                .unwrap(),
-            };
-        }
-
-        logger::log(sentry::Breadcrumb {
-            category: Some("bus".to_string()),
-            message: Some(format!("end user {}:{}", &user.school, &user.username)),
-            level: sentry::Level::Info,
-            ..Default::default()
-        });
+    let selectors = matches.opt_strs("u");
+    let run_options = commands::run::RunOptions {
+        dry_run: matches.opt_present("dry-run"),
+        jobs: matches.opt_get("j").unwrap_or_else(|err| {
+            eprintln!("Invalid --jobs: {}", err);
+            std::process::exit(1);
+        }),
+    };
+    let args: Vec<&str> = matches.free.iter().map(String::as_str).collect();
+    let result = match args.as_slice() {
+        [] | ["run"] => commands::run::run(&config_file_path, &selectors, run_options),
+        ["daemon"] => commands::daemon::daemon(&config_file_path, &selectors, run_options),
+        ["login-test"] => commands::login_test(&config_file_path, &selectors),
+        ["tenants", "search", query] => commands::tenants::search(query),
+        ["tenants", "show", id] => commands::tenants::show(id),
+        ["forms", "list"] => commands::forms::list(&config_file_path, &selectors),
+        ["forms", "show", wid] => commands::forms::show(&config_file_path, &selectors, wid),
+        ["crypto", "decrypt", text] => commands::crypto::decrypt(
+            text,
+            &matches.opt_str("key").unwrap_or_else(|| "f".to_string()),
+        ),
+        ["config", "validate"] => commands::validate_config(&config_file_path),
+        ["config", "encrypt"] => config::encryption::encrypt_file(&config_file_path),
+        ["config", "decrypt"] => config::encryption::decrypt_file(&config_file_path),
+        ["config", "edit"] => config::encryption::edit_file(&config_file_path),
+        ["config", "add-user"] | ["init"] => wizard::add_user(&config_file_path),
+        _ => Err(anyhow::anyhow!(
+            "Unknown command \"{}\", see --help",
+            args.join(" ")
+        )),
+    };
+    if let Err(err) = result {
+        eprintln!("{:#}", err);
+        std::process::exit(1);
     }
 }
 
@@ -153,7 +80,35 @@ fn parse_options() -> Matches {
     let program = args[0].clone();
 
     let mut opts = Options::new();
-    opts.optopt("c", "config", "config file (default: config.yml)", "PATH");
+    opts.optopt(
+        "c",
+        "config",
+        "config file or directory (default: config.yml)",
+        "PATH",
+    );
+    opts.optmulti(
+        "u",
+        "user",
+        "only act on this user, may be repeated",
+        "SCHOOL:USERNAME",
+    );
+    opts.optopt(
+        "j",
+        "jobs",
+        "how many users to work on at once (default: concurrency.users of the config)",
+        "N",
+    );
+    opts.optflag(
+        "",
+        "dry-run",
+        "fill in the forms and print them instead of submitting",
+    );
+    opts.optopt(
+        "",
+        "key",
+        "first_v2 key to decrypt with, c or f (default: f)",
+        "KEY",
+    );
     opts.optflag("h", "help", "print this help menu");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => m,
@@ -168,6 +123,23 @@ fn parse_options() -> Matches {
 }
 
 fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
+    let brief = format!(
+        "Usage: {} [options] [COMMAND]\n\n\
+         Commands:\n    \
+         run                    run the actions of every user (default)\n    \
+         daemon                 run the actions on their schedules, SIGHUP reloads\n    \
+         login-test             log in every user without running actions\n    \
+         tenants search QUERY   find a school by id or name\n    \
+         tenants show ID        show how a school logs in\n    \
+         forms list             list the forms of every user\n    \
+         forms show WID         show the questions of a form\n    \
+         crypto decrypt TEXT    decrypt a first_v2 payload\n    \
+         config validate        check the config file for mistakes\n    \
+         config encrypt         encrypt the config file in place\n    \
+         config decrypt         decrypt the config file in place\n    \
+         config edit            edit an encrypted config file\n    \
+         config add-user        add a user to the config file step by step (alias: init)",
+        program
+    );
     print!("{}", opts.usage(&brief));
 }
@@ -0,0 +1,319 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use anyhow::{anyhow, bail};
+use serde_yaml::{Mapping, Value};
+
+use crate::config::{device_profile, encryption, Password, PasswordRef, User};
+use crate::cpdaily::{self, client, session, structs::tenants::Tenant};
+
+fn prompt(label: &str, default: Option<&str>) -> anyhow::Result<String> {
+    match default {
+        Some(default) => print!("{} [{}]: ", label, default),
+        None => print!("{}: ", label),
+    }
+    io::stdout().flush()?;
+    let mut line = String::new();
+    if io::stdin().read_line(&mut line)? == 0 {
+        bail!("Aborted");
+    }
+    let line = line.trim();
+    Ok(match (line.is_empty(), default) {
+        (true, Some(default)) => default.to_owned(),
+        _ => line.to_owned(),
+    })
+}
+
+fn prompt_parse<T: FromStr>(label: &str, default: Option<&str>) -> anyhow::Result<T> {
+    loop {
+        match prompt(label, default)?.parse() {
+            Ok(value) => return Ok(value),
+            Err(_) => println!("Not a valid value, try again"),
+        }
+    }
+}
+
+fn prompt_yes(label: &str) -> anyhow::Result<bool> {
+    let answer = prompt(&format!("{} [y/N]", label), None)?;
+    Ok(answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes"))
+}
+
+fn pick_tenant(tenants: &[Tenant]) -> anyhow::Result<&Tenant> {
+    loop {
+        let query = prompt("Search school", None)?;
+        let matches: Vec<&Tenant> = tenants
+            .iter()
+            .filter(|tenant| tenant.id == query || tenant.name.contains(&query))
+            .collect();
+        match matches.len() {
+            0 => println!("No school found, try again"),
+            n if n > 20 => println!("{} schools found, be more specific", n),
+            _ => {
+                for (i, tenant) in matches.iter().enumerate() {
+                    println!("  {}) {} ({})", i + 1, tenant.name, tenant.id);
+                }
+                let choice: usize = prompt_parse("Pick one, 0 to search again", Some("1"))?;
+                if choice > 0 && choice <= matches.len() {
+                    return Ok(matches[choice - 1]);
+                }
+            }
+        }
+    }
+}
+
+fn prompt_form_data() -> anyhow::Result<Value> {
+    println!("Form answers, leave the question empty to finish");
+    let mut form_data = vec![];
+    loop {
+        let question = prompt("  Question (part of its title)", Some(""))?;
+        if question.is_empty() {
+            break;
+        }
+        let answer = prompt("  Answer", None)?;
+        let mut qa = Mapping::new();
+        qa.insert(Value::from("question"), Value::from(question));
+        qa.insert(Value::from("answer"), Value::from(answer));
+        form_data.push(Value::Mapping(qa));
+    }
+    let mut action = Mapping::new();
+    action.insert(Value::from("type"), Value::from("CounselorFormFill"));
+    action.insert(Value::from("force_submit"), Value::from(false));
+    action.insert(Value::from("form_data"), Value::Sequence(form_data));
+    Ok(Value::Sequence(vec![Value::Mapping(action)]))
+}
+
+fn test_login(user: &User, tenant: &Tenant) -> anyhow::Result<()> {
+    let cookie_jar = Arc::new(session::CookieJar::default());
+    let client = client::new(user, cookie_jar)?;
+    let login_provider = tenant.create_login(user.get_login_options())?;
+    println!("Logging in with {}...", login_provider.get_type());
+    login_provider.login(&client, &user.username, &user.password.resolve()?)?;
+    Ok(())
+}
+
+// the user as a yaml sequence item, indented to sit under `users:`
+fn user_block(user: &Mapping, indent: usize) -> anyhow::Result<String> {
+    let yaml = serde_yaml::to_string(user)?;
+    let yaml = yaml.trim_start_matches("---\n");
+    let mut block = String::new();
+    for (i, line) in yaml.lines().enumerate() {
+        let marker = if i == 0 { "- " } else { "  " };
+        block.push_str(&format!("{}{}{}\n", " ".repeat(indent), marker, line));
+    }
+    Ok(block)
+}
+
+// append to the text so comments and layout survive, which only works
+// when `users` is the last top-level key
+fn append_user(contents: &str, user: &Mapping) -> anyhow::Result<String> {
+    if contents.trim().is_empty() {
+        return Ok(format!("users:\n{}", user_block(user, 2)?));
+    }
+
+    let lines: Vec<&str> = contents.lines().collect();
+    let top_level = |line: &&str| {
+        !line.is_empty()
+            && !line.starts_with(' ')
+            && !line.starts_with('-')
+            && !line.starts_with('#')
+    };
+    let users_line = lines
+        .iter()
+        .rposition(top_level)
+        .filter(|i| lines[*i].trim_end() == "users:")
+        .ok_or_else(|| anyhow!("`users:` is not the last key of the config"))?;
+    let indent = lines[users_line + 1..]
+        .iter()
+        .find(|line| line.trim_start().starts_with("- "))
+        .map(|line| line.len() - line.trim_start().len())
+        .unwrap_or(2);
+
+    let mut out = contents.to_owned();
+    if !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&user_block(user, indent)?);
+
+    // make sure the user landed where we think it did
+    let before: Value = serde_yaml::from_str(contents)?;
+    let after: Value = serde_yaml::from_str(&out)?;
+    let count = |value: &Value| value["users"].as_sequence().map_or(0, |users| users.len());
+    if count(&after) != count(&before) + 1
+        || after["users"][count(&before)] != Value::Mapping(user.clone())
+    {
+        bail!("Could not append the user to the config");
+    }
+    Ok(out)
+}
+
+fn save_user(config_path: &str, user: &Mapping, file_name: &str) -> anyhow::Result<String> {
+    let path = Path::new(config_path);
+    if path.is_dir() {
+        let path = path.join(format!("{}.yml", file_name));
+        if path.exists() {
+            bail!("{} already exists", path.display());
+        }
+        session::write_atomic(
+            &path,
+            serde_yaml::to_string(user)?
+                .trim_start_matches("---\n")
+                .as_bytes(),
+            0o600,
+        )?;
+        return Ok(path.display().to_string());
+    }
+
+    let contents = if path.exists() {
+        fs::read(path)?
+    } else {
+        vec![]
+    };
+    let appended = if encryption::is_encrypted(&contents) {
+        let passphrase = encryption::get_passphrase(false)?;
+        let plaintext = encryption::decrypt(&contents, &passphrase)?;
+        let appended = append_user(&String::from_utf8(plaintext)?, user)?;
+        encryption::encrypt(appended.as_bytes(), &passphrase)?
+    } else {
+        append_user(&String::from_utf8(contents)?, user)?
+    };
+    // the config may hold passwords, new files are private and rewritten
+    // ones keep the mode they had
+    session::write_atomic(path, appended.as_bytes(), session::file_mode(path, 0o600))?;
+    Ok(config_path.to_owned())
+}
+
+// the password itself, or a reference to an environment variable that the
+// user sets up on their own
+fn password_entry(password: &str, file_stem: &str) -> anyhow::Result<Value> {
+    if !prompt_yes("Keep the password out of the config and read it from an environment variable?")?
+    {
+        return Ok(Value::from(password));
+    }
+    let default_name = format!("PASSWORD_{}", file_stem.to_uppercase().replace('-', "_"));
+    let name = prompt("Environment variable", Some(&default_name))?;
+    println!("Remember to set {} before running", name);
+    Ok(serde_yaml::to_value(Password::Ref(PasswordRef::Env {
+        name,
+    }))?)
+}
+
+pub fn add_user(config_path: &str) -> anyhow::Result<()> {
+    println!("Fetching school list...");
+    let tenants = cpdaily::get_all_tenants()?;
+    let tenant = pick_tenant(&tenants)?;
+
+    let username = prompt("Username", None)?;
+    print!("Password (leave empty to fill in later): ");
+    io::stdout().flush()?;
+    let password = encryption::read_line_hidden()?;
+    let address = prompt("Address", None)?;
+    let lat: f64 = prompt_parse("Latitude", None)?;
+    let lon: f64 = prompt_parse("Longitude", None)?;
+    let actions = prompt_form_data()?;
+
+    let file_stem = session::get_user_file_stem(&tenant.id, &username);
+    let mut block = Mapping::new();
+    block.insert(Value::from("school"), Value::from(tenant.id.clone()));
+    block.insert(Value::from("username"), Value::from(username.clone()));
+    if !password.is_empty() {
+        block.insert(
+            Value::from("password"),
+            password_entry(&password, &file_stem)?,
+        );
+    }
+    block.insert(Value::from("address"), Value::from(address));
+    // the rest of device_info comes from the profile when loading
+    let mut device_info = Mapping::new();
+    device_info.insert(Value::from("lat"), Value::from(lat));
+    device_info.insert(Value::from("lon"), Value::from(lon));
+    block.insert(Value::from("device_profile"), Value::from("auto"));
+    block.insert(
+        Value::from("device_info"),
+        Value::Mapping(device_info.clone()),
+    );
+    block.insert(Value::from("actions"), actions);
+
+    let mut user = Value::Mapping(block.clone());
+    device_profile::apply(&mut user, Some(&device_info))?;
+    let mut user: User = serde_yaml::from_value(user)?;
+    // the login test uses what was typed, wherever it is kept later on
+    user.password = Password::Plain(password.clone());
+
+    if !password.is_empty() && prompt_yes("Test the login now?")? {
+        match test_login(&user, tenant) {
+            Ok(()) => println!("Logged in"),
+            Err(err) => {
+                println!("Login failed: {:#}", err);
+                if !prompt_yes("Save the user anyway?")? {
+                    bail!("User not saved");
+                }
+            }
+        }
+    }
+
+    let saved_to = save_user(config_path, &block, &file_stem)?;
+    println!("Added {} to {}", username, saved_to);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_yaml::{Mapping, Value};
+
+    use super::{append_user, save_user};
+
+    fn user(username: &str) -> Mapping {
+        let mut user = Mapping::new();
+        user.insert(Value::from("school"), Value::from("10000"));
+        user.insert(Value::from("username"), Value::from(username));
+        user
+    }
+
+    #[test]
+    fn test_append_user() {
+        let contents = "# my config\nsession_dir: sessions\nusers:\n    # first one\n    - school: \"10000\"\n      username: alice\n";
+        assert_eq!(
+            append_user(contents, &user("bob")).unwrap(),
+            format!("{}    - school: \"10000\"\n      username: bob\n", contents)
+        );
+        assert_eq!(
+            append_user("", &user("bob")).unwrap(),
+            "users:\n  - school: \"10000\"\n    username: bob\n"
+        );
+        assert!(append_user("users: []\nsession_dir: sessions\n", &user("bob")).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_save_user_mode() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let mode =
+            |path: &std::path::Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        let dir = std::env::temp_dir().join(format!("wizard-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+
+        let saved = save_user(dir.to_str().unwrap(), &user("alice"), "10000-alice").unwrap();
+        assert_eq!(mode(std::path::Path::new(&saved)), 0o600);
+        assert!(save_user(dir.to_str().unwrap(), &user("alice"), "10000-alice").is_err());
+
+        let config = dir.join("config.yml");
+        save_user(config.to_str().unwrap(), &user("alice"), "").unwrap();
+        assert_eq!(mode(&config), 0o600);
+        fs::set_permissions(&config, fs::Permissions::from_mode(0o640)).unwrap();
+        save_user(config.to_str().unwrap(), &user("bob"), "").unwrap();
+        assert_eq!(mode(&config), 0o640);
+        assert_eq!(
+            fs::read_to_string(&config).unwrap(),
+            "users:\n  - school: \"10000\"\n    username: alice\n  - school: \"10000\"\n    username: bob\n"
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}